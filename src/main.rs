@@ -8,6 +8,7 @@ use tcod::input::{self, Event, Key, Mouse};
 use rand::Rng;
 
 use std::cmp;
+use std::collections::HashMap;
 
 use std::error::Error;
 use std::fs::File;
@@ -28,12 +29,19 @@ const PANEL_HEIGHT: i32 = SCREEN_HEIGHT - MAP_HEIGHT;
 const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 
 const INVENTORY_WIDTH: i32 = 50;
+const MENU_PAGE_SIZE: usize = 26;
 
-const CHOOSE_MENU_WIDTH: i32 = 50;
-
-const LEVEL_SCREEN_WIDTH: i32 = 40;
+const LEVEL_SCREEN_WIDTH: i32 = 64;
 
 const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const CLASS_SCREEN_WIDTH: i32 = 60;
+
+const DIALOGUE_WIDTH: i32 = 60;
+
+const QUEST_LOG_WIDTH: i32 = 60;
+
+const EQUIPMENT_SCREEN_WIDTH: i32 = 66;
+const EQUIPMENT_SCREEN_HEIGHT: i32 = 22;
 
 const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
@@ -57,16 +65,149 @@ const COLOR_LIGHT_GROUND: Color = Color {
     b: 50,
 };
 
+const COLOR_TARGET_PATH: Color = Color {
+    r: 80,
+    g: 200,
+    b: 200,
+};
+const COLOR_TARGET_BLOCKED: Color = Color {
+    r: 150,
+    g: 40,
+    b: 40,
+};
+const COLOR_TARGET_AREA: Color = Color {
+    r: 220,
+    g: 110,
+    b: 30,
+};
+
 const MANA_REGENERATION: i32 = 15;
 
+const HP_REGEN_FACTOR: f32 = 0.005;
+const MANA_REGEN_FACTOR: f32 = 0.01;
+const MANA_REGEN_PER_SPELLCAST: f32 = 0.02;
+const REST_MAX_TURNS: i32 = 1000;
+
+const START_NUTRITION: i32 = 1000;
+const MAX_NUTRITION: i32 = 2000;
+const SATIATED_NUTRITION: i32 = 1500;
+const HUNGRY_NUTRITION: i32 = 300;
+const WEAK_NUTRITION: i32 = 100;
+const STARVATION_INTERVAL: i32 = 5;
+const STARVATION_DAMAGE: i32 = 1;
+
+const RATION_NUTRITION: i32 = 800;
+
+const CURSED_CHANCE: f32 = 0.1;
+const BLESSED_CHANCE: f32 = 0.1;
+
+const INVENTORY_SLOTS: usize = 20;
+const BAG_CAPACITY: usize = 10;
+const CHEST_CAPACITY: usize = 8;
+const CHEST_CHANCE: f32 = 0.15;
+const CHEST_MAX_ITEMS: i32 = 3;
+const CHEST_LOCKED_CHANCE: f32 = 0.3;
+const CHEST_TRAPPED_CHANCE: f32 = 0.2;
+const FORCE_LOCK_CHANCE: f32 = 0.4;
+const CHEST_TRAP_DAMAGE: i32 = 6;
+
+const GOLD_MIN: i32 = 5;
+const GOLD_MAX: i32 = 15;
+const MONSTER_GOLD_CHANCE: f32 = 0.5;
+const SHOP_CHANCE: f32 = 0.3;
+const SHOP_ITEM_CHANCE: f32 = 0.5;
+
+const EQUIPMENT_DURABILITY: i32 = 40;
+const WEAR_CHANCE: f32 = 0.3;
+const ANVIL_CHANCE: f32 = 0.25;
+
+const AFFIX_BASE_CHANCE: u32 = 10;
+const AFFIX_CHANCE_PER_LEVEL: u32 = 5;
+const AFFIX_MAX_CHANCE: u32 = 50;
+const AFFIX_LEVELS_PER_STEP: i32 = 3;
+
+const POTION_KINDS: &[&str] = &["healing potion", "mana potion"];
+const SCROLL_KINDS: &[&str] = &[
+    "scroll of lightning",
+    "scroll of fireball",
+    "scroll of confusion",
+    "scroll of identify",
+    "scroll of repair",
+    "scroll of remove curse",
+    "scroll of recharging",
+    "scroll of magic mapping",
+    "scroll of teleportation",
+    "scroll of detect monsters",
+    "scroll of remove fog",
+];
+const WAND_KINDS: &[&str] = &[
+    "wand of digging",
+    "wand of slowness",
+    "wand of teleportation",
+    "wand of lightning",
+];
+
+const POTION_APPEARANCES: &[&str] = &[
+    "murky purple",
+    "bubbling green",
+    "smoky black",
+    "glowing orange",
+    "milky white",
+    "fizzy red",
+    "oily brown",
+    "sparkling blue",
+    "cloudy grey",
+    "golden",
+];
+const SCROLL_LABELS: &[&str] = &[
+    "XYZZY",
+    "FOOBIE BLETCH",
+    "ZELGO MER",
+    "ELAM EBOW",
+    "VERR YED HORRE",
+    "KIRJE",
+    "THARR",
+    "PRATYAVAYAH",
+    "DAIYEN FOOELS",
+    "JUYED AWK YACC",
+    "NR 9",
+    "ANDOVA BEGARIN",
+];
+const WAND_MATERIALS: &[&str] = &[
+    "oak", "iron", "bone", "glass", "copper", "ebony", "crystal", "silver",
+];
+const TROLL_CORPSE_HEAL: i32 = 20;
+const INGREDIENT_CHANCE: f32 = 0.5;
+const SICKNESS_DAMAGE: i32 = 5;
+
 const PLAYER: usize = 0;
 
+const MONSTER_KINDS: &[&str] = &["orc", "troll", "archer"];
+
 const DAMAGE_SCALING: f32 = 0.5;
 const DAMAGE_OFFSET: f32 = -0.5;
 
+const BASE_HIT_CHANCE: f32 = 0.85;
+const HIT_CHANCE_PER_POINT: f32 = 0.05;
+const MIN_HIT_CHANCE: f32 = 0.05;
+const MAX_HIT_CHANCE: f32 = 0.95;
+
+const BASE_CRITICAL_CHANCE: f32 = 0.05;
+const CRITICAL_CHANCE_PER_POINT: f32 = 0.01;
+const CRITICAL_MULTIPLIER: f32 = 2.0;
+
 const LEVEL_UP_BASE: i32 = 350;
 const LEVEL_UP_FACTOR: i32 = 150;
 
+const ATTRIBUTE_AVERAGE: i32 = 10;
+const ATTRIBUTE_LEVEL_UP: i32 = 2;
+const HP_PER_CONSTITUTION: i32 = 10;
+const MANA_PER_WILLPOWER: i32 = 10;
+
+const PERK_TIER_LEVEL: i32 = 4;
+const DODGE_CHANCE: f32 = 0.2;
+const BLOODLUST_HEAL: i32 = 5;
+
 const CONFUSED_NUM_TURNS: i32 = 10;
 const CONFUSED_RANGE: i32 = 8;
 
@@ -85,12 +226,48 @@ const LIGHTNING_RANGE: i32 = 5;
 const FIREBALL_RANGE: i32 = 8;
 const FIREBALL_CHARGES: i32 = 3;
 const FIREBALL_DAMAGE: i32 = 25;
+const FIREBALL_RADIUS: i32 = 3;
 
-fn calculate_damage(base_damage: f32, defense: f32) -> i32 {
+const WAND_MIN_CHARGES: i32 = 4;
+const WAND_MAX_CHARGES: i32 = 8;
+const WAND_RANGE: i32 = 8;
+const WAND_LIGHTNING_DAMAGE: i32 = 30;
+const WAND_EXPLOSION_DAMAGE: i32 = 10;
+const SLOWED_NUM_TURNS: i32 = 20;
+const RECHARGE_EXPLODE_CHANCE: f32 = 0.25;
+
+const DETECT_MONSTERS_TURNS: i32 = 20;
+const REMOVE_FOG_RADIUS: i32 = 40;
+
+const SPELL_BASE_FAILURE: f32 = 0.05;
+const SPELL_FAILURE_PER_POINT: f32 = 0.1;
+const SPELL_MAX_FAILURE: f32 = 0.9;
+const MAGIC_MISSILE_RANGE: i32 = 8;
+
+const THROW_RANGE: i32 = 6;
+const THROWN_DAMAGE: i32 = 1;
+
+fn expected_damage(base_damage: f32, defense: f32) -> f32 {
     let sigmoid = |x: f32| -> f32 { 1.0 / (1.0 + (-x).exp()) };
 
-    let damage = base_damage * sigmoid((base_damage - defense) * DAMAGE_SCALING + DAMAGE_OFFSET);
+    base_damage * sigmoid((base_damage - defense) * DAMAGE_SCALING + DAMAGE_OFFSET)
+}
+
+// spell penetration lets the player's magic ignore half of a monster's defense
+fn magic_defense(target: &Object, game: &Game) -> f32 {
+    let defense = target.defense(game) as f32;
+    if target.name != "player" && game.has_perk(Perk::SpellPenetration) {
+        defense / 2.0
+    } else {
+        defense
+    }
+}
+
+fn calculate_damage(base_damage: f32, defense: f32) -> i32 {
+    round_randomly(expected_damage(base_damage, defense))
+}
 
+fn round_randomly(damage: f32) -> i32 {
     let rest = if rand::thread_rng().gen::<f32>() < damage.fract() {
         1
     } else {
@@ -100,6 +277,76 @@ fn calculate_damage(base_damage: f32, defense: f32) -> i32 {
     damage.floor() as i32 + rest
 }
 
+fn hit_chance(accuracy: i32, evasion: i32) -> f32 {
+    let chance = BASE_HIT_CHANCE + (accuracy - evasion) as f32 * HIT_CHANCE_PER_POINT;
+    chance.clamp(MIN_HIT_CHANCE, MAX_HIT_CHANCE)
+}
+
+fn critical_chance(critical: i32) -> f32 {
+    let chance = BASE_CRITICAL_CHANCE + critical as f32 * CRITICAL_CHANCE_PER_POINT;
+    chance.clamp(0.0, 1.0)
+}
+
+// prints the chance of every melee outcome, to balance the damage sigmoid without playing
+fn print_damage_table(args: &[String]) {
+    let numbers: Result<Vec<i32>, _> = args.iter().map(|arg| arg.parse()).collect();
+    let numbers = match numbers {
+        Ok(ref numbers) if numbers.len() >= 2 => numbers,
+        _ => {
+            println!("usage: --damage-table <power> <defense> [accuracy] [evasion] [critical]");
+            return;
+        }
+    };
+
+    let arg = |index: usize| numbers.get(index).copied().unwrap_or(0);
+    let (power, defense) = (arg(0), arg(1));
+    let (accuracy, evasion, critical) = (arg(2), arg(3), arg(4));
+
+    let hit = hit_chance(accuracy, evasion);
+    let crit = critical_chance(critical);
+
+    let mut outcomes = std::collections::BTreeMap::new();
+    let normal = expected_damage(power as f32, defense as f32);
+    for &(damage, chance) in &[
+        (normal, hit * (1.0 - crit)),
+        (normal * CRITICAL_MULTIPLIER, hit * crit),
+    ] {
+        *outcomes.entry(damage.floor() as i32).or_insert(0.0) += chance * (1.0 - damage.fract());
+        *outcomes.entry(damage.floor() as i32 + 1).or_insert(0.0) += chance * damage.fract();
+    }
+
+    println!(
+        "power {} vs defense {} (accuracy {}, evasion {}, critical {})",
+        power, defense, accuracy, evasion, critical
+    );
+    println!(
+        "DAMAGE_SCALING = {}, DAMAGE_OFFSET = {}",
+        DAMAGE_SCALING, DAMAGE_OFFSET
+    );
+    println!(
+        "hit chance {:.1}%, critical chance {:.1}%\n",
+        hit * 100.0,
+        crit * 100.0
+    );
+
+    let print_row = |label: String, chance: f32| {
+        let bar = "#".repeat((chance * 50.0).round() as usize);
+        println!("{:>6} {:>6.2}% {}", label, chance * 100.0, bar);
+    };
+
+    print_row("miss".into(), 1.0 - hit);
+    for (damage, chance) in outcomes {
+        if chance > 0.0 {
+            print_row(damage.to_string(), chance);
+        }
+    }
+
+    println!(
+        "\nexpected damage per attack: {:.2}",
+        hit * normal * (1.0 - crit + crit * CRITICAL_MULTIPLIER)
+    );
+}
+
 #[derive(Serialize, Deserialize)]
 struct Messages {
     messages: Vec<(String, Color)>,
@@ -207,101 +454,343 @@ fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
         .map_or(0, |transition| transition.value)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum OnHit {
+    Burn { damage: i32 },
+    Drain { amount: i32 },
+    Confuse { num_turns: i32 },
+}
+
+impl std::fmt::Display for OnHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            OnHit::Burn { damage } => write!(f, "burns for {}", damage),
+            OnHit::Drain { amount } => write!(f, "drains {} HP", amount),
+            OnHit::Confuse { num_turns } => write!(f, "confuses for {} turns", num_turns),
+        }
+    }
+}
+
+// an effect that happens to the target (and sometimes the attacker) after a successful hit
+fn apply_on_hit(effect: OnHit, attacker: &mut Object, target: &mut Object, game: &mut Game) {
+    if !target.alive {
+        return;
+    }
+    match effect {
+        OnHit::Burn { damage } => {
+            game.messages.add(
+                format!("{} is burned for {} hp", target.name, damage),
+                ORANGE,
+            );
+            if let Some(xp) = target.take_damage(damage, game) {
+                attacker.get_xp(xp);
+            }
+        }
+        OnHit::Drain { amount } => {
+            game.messages.add(
+                format!(
+                    "{} drains {} hp of life from {}",
+                    attacker.name, amount, target.name
+                ),
+                LIGHT_RED,
+            );
+            if let Some(xp) = target.take_damage(amount, game) {
+                attacker.get_xp(xp);
+            }
+            attacker.heal(amount, game);
+        }
+        OnHit::Confuse { num_turns } => {
+            if let Some(ai) = target.ai.take() {
+                target.ai = Some(match ai {
+                    Ai::Confused { .. } => ai,
+                    _ => {
+                        game.messages
+                            .add(format!("{} looks confused", target.name), LIGHT_BLUE);
+                        Ai::Confused {
+                            previous_ai: Box::new(ai),
+                            num_turns,
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AffixBonus {
+    Power,
+    Defense,
+    MaxHp,
+    MaxMana,
+    Spellcast,
+    Accuracy,
+    Evasion,
+    Critical,
+    HpRegen,
+    ManaRegen,
+    Burn,
+    Drain,
+    Confuse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AffixKind {
+    Weapon,
+    Armor,
+    Any,
+}
+
+struct Affix {
+    name: &'static str,
+    bonus: AffixBonus,
+    kind: AffixKind,
+    min_level: u32,
+    // the bonus is a multiple of this, rolled higher on deeper levels
+    scale: i32,
+}
+
+// only prefixes have on-hit effects, so an item never has more than one
+const PREFIXES: &[Affix] = &[
+    Affix {
+        name: "sharp",
+        bonus: AffixBonus::Power,
+        kind: AffixKind::Weapon,
+        min_level: 1,
+        scale: 1,
+    },
+    Affix {
+        name: "keen",
+        bonus: AffixBonus::Critical,
+        kind: AffixKind::Weapon,
+        min_level: 1,
+        scale: 3,
+    },
+    Affix {
+        name: "flaming",
+        bonus: AffixBonus::Burn,
+        kind: AffixKind::Weapon,
+        min_level: 2,
+        scale: 2,
+    },
+    Affix {
+        name: "dazzling",
+        bonus: AffixBonus::Confuse,
+        kind: AffixKind::Weapon,
+        min_level: 3,
+        scale: 2,
+    },
+    Affix {
+        name: "vampiric",
+        bonus: AffixBonus::Drain,
+        kind: AffixKind::Weapon,
+        min_level: 4,
+        scale: 2,
+    },
+    Affix {
+        name: "sturdy",
+        bonus: AffixBonus::Defense,
+        kind: AffixKind::Armor,
+        min_level: 1,
+        scale: 1,
+    },
+    Affix {
+        name: "nimble",
+        bonus: AffixBonus::Evasion,
+        kind: AffixKind::Any,
+        min_level: 1,
+        scale: 1,
+    },
+    Affix {
+        name: "runed",
+        bonus: AffixBonus::Spellcast,
+        kind: AffixKind::Any,
+        min_level: 3,
+        scale: 1,
+    },
+];
+
+const SUFFIXES: &[Affix] = &[
+    Affix {
+        name: "of the bear",
+        bonus: AffixBonus::MaxHp,
+        kind: AffixKind::Any,
+        min_level: 1,
+        scale: 5,
+    },
+    Affix {
+        name: "of the hawk",
+        bonus: AffixBonus::Accuracy,
+        kind: AffixKind::Any,
+        min_level: 1,
+        scale: 1,
+    },
+    Affix {
+        name: "of the magi",
+        bonus: AffixBonus::MaxMana,
+        kind: AffixKind::Any,
+        min_level: 2,
+        scale: 5,
+    },
+    Affix {
+        name: "of the troll",
+        bonus: AffixBonus::HpRegen,
+        kind: AffixKind::Any,
+        min_level: 3,
+        scale: 25,
+    },
+    Affix {
+        name: "of meditation",
+        bonus: AffixBonus::ManaRegen,
+        kind: AffixKind::Any,
+        min_level: 3,
+        scale: 25,
+    },
+];
+
+impl Affix {
+    fn fits(&self, equipment: &Equipment, level: u32) -> bool {
+        let weapon = equipment.slot == Slot::RightHand;
+        level >= self.min_level
+            && match self.kind {
+                AffixKind::Weapon => weapon,
+                AffixKind::Armor => !weapon,
+                AffixKind::Any => true,
+            }
+    }
+
+    fn apply(&self, equipment: &mut Equipment, level: u32) {
+        let magnitude = rand::thread_rng().gen_range(1, 2 + level as i32 / AFFIX_LEVELS_PER_STEP);
+        let value = magnitude * self.scale;
+        match self.bonus {
+            AffixBonus::Power => equipment.power_bonus += value,
+            AffixBonus::Defense => equipment.defense_bonus += value,
+            AffixBonus::MaxHp => equipment.max_hp_bonus += value,
+            AffixBonus::MaxMana => equipment.max_mana_bonus += value,
+            AffixBonus::Spellcast => equipment.spellcast_bonus += value,
+            AffixBonus::Accuracy => equipment.accuracy_bonus += value,
+            AffixBonus::Evasion => equipment.evasion_bonus += value,
+            AffixBonus::Critical => equipment.critical_bonus += value,
+            AffixBonus::HpRegen => equipment.hp_regen_bonus += value,
+            AffixBonus::ManaRegen => equipment.mana_regen_bonus += value,
+            AffixBonus::Burn => equipment.on_hit = Some(OnHit::Burn { damage: value }),
+            AffixBonus::Drain => equipment.on_hit = Some(OnHit::Drain { amount: value }),
+            AffixBonus::Confuse => equipment.on_hit = Some(OnHit::Confuse { num_turns: value }),
+        }
+    }
+}
+
+// picks the index of an affix that fits the equipment, if it gets one at all
+fn roll_affix(affixes: &[Affix], equipment: &Equipment, level: u32) -> Option<usize> {
+    let chance = cmp::min(
+        AFFIX_BASE_CHANCE + AFFIX_CHANCE_PER_LEVEL * level,
+        AFFIX_MAX_CHANCE,
+    );
+    if rand::thread_rng().gen_range(0, 100) >= chance {
+        return None;
+    }
+    let fitting: Vec<usize> = (0..affixes.len())
+        .filter(|&id| affixes[id].fits(equipment, level))
+        .collect();
+    rand::thread_rng().choose(&fitting).cloned()
+}
+
+// gives a freshly generated weapon or armor a random prefix and suffix
+fn add_affixes(object: &mut Object, level: u32) {
+    let mut equipment = match object.equipment {
+        Some(equipment) if equipment.slot != Slot::Ammunition => equipment,
+        _ => return,
+    };
+    equipment.prefix = roll_affix(PREFIXES, &equipment, level);
+    equipment.suffix = roll_affix(SUFFIXES, &equipment, level);
+    if let Some(prefix) = equipment.prefix {
+        PREFIXES[prefix].apply(&mut equipment, level);
+    }
+    if let Some(suffix) = equipment.suffix {
+        SUFFIXES[suffix].apply(&mut equipment, level);
+    }
+    object.equipment = Some(equipment);
+}
+
+// a two-handed weapon is worn in the right hand but also takes up the left
 fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
     for (inventory_id, item) in inventory.iter().enumerate() {
-        if item
-            .equipment
-            .as_ref()
-            .map_or(false, |e| e.equipped && e.slot == slot)
-        {
+        if item.equipment.as_ref().map_or(false, |e| {
+            e.equipped && (e.slot == slot || (e.two_handed && slot == Slot::LeftHand))
+        }) {
             return Some(inventory_id);
         }
     }
     None
 }
 
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
-    use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
+// picks the slot to wear `equipment` in and lists the equipped items that would have to come off
+fn slot_conflicts(equipment: &Equipment, inventory: &[Object]) -> (Slot, Vec<usize>) {
+    let slot = match equipment.slot {
+        Slot::LeftRing | Slot::RightRing => [Slot::LeftRing, Slot::RightRing]
+            .iter()
+            .cloned()
+            .find(|&ring| get_equipped_in_slot(ring, inventory).is_none())
+            .unwrap_or(equipment.slot),
+        slot => slot,
+    };
+    let slots = if equipment.two_handed {
+        vec![Slot::RightHand, Slot::LeftHand]
+    } else {
+        vec![slot]
+    };
 
-    let max_monsters = from_dungeon_level(
-        &[
-            Transition { level: 0, value: 2 },
-            Transition { level: 4, value: 3 },
-            Transition { level: 6, value: 5 },
-            Transition { level: 9, value: 8 },
-            Transition {
-                level: 14,
-                value: 13,
-            },
-        ],
-        level,
-    );
+    let mut conflicts: Vec<usize> = slots
+        .iter()
+        .filter_map(|&slot| get_equipped_in_slot(slot, inventory))
+        .collect();
+    conflicts.dedup();
+    (slot, conflicts)
+}
 
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+// sometimes wears down one of the equipped items picked by `wears`. A worn out item breaks and
+// comes off, but stays in the inventory until the turn is over, as callers may hold its index
+fn wear_equipment(game: &mut Game, wears: impl Fn(&Equipment) -> bool) {
+    if rand::thread_rng().gen::<f32>() >= WEAR_CHANCE {
+        return;
+    }
+    let worn: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| {
+            game.inventory[id]
+                .equipment
+                .is_some_and(|e| e.equipped && e.slot != Slot::Ammunition && wears(&e))
+        })
+        .collect();
 
-    let monster_chances = &mut [
-        Weighted {
-            weight: from_dungeon_level(
-                &[
-                    Transition {
-                        level: 3,
-                        value: 15,
-                    },
-                    Transition {
-                        level: 5,
-                        value: 30,
-                    },
-                    Transition {
-                        level: 7,
-                        value: 60,
-                    },
-                    Transition {
-                        level: 12,
-                        value: 100,
-                    },
-                ],
-                level,
-            ),
-            item: "troll",
-        },
-        Weighted {
-            weight: from_dungeon_level(
-                &[
-                    Transition {
-                        level: 4,
-                        value: 30,
-                    },
-                    Transition {
-                        level: 6,
-                        value: 80,
-                    },
-                    Transition {
-                        level: 8,
-                        value: 90,
-                    },
-                ],
-                level,
-            ),
-            item: "archer",
-        },
-        Weighted {
-            weight: 80,
-            item: "orc",
-        },
-    ];
-    let monster_choice = WeightedChoice::new(monster_chances);
+    if let Some(&id) = rand::thread_rng().choose(&worn) {
+        let broken = match game.inventory[id].equipment {
+            Some(ref mut equipment) => {
+                equipment.durability -= 1;
+                if equipment.durability <= 0 {
+                    equipment.equipped = false;
+                }
+                equipment.durability <= 0
+            }
+            None => false,
+        };
+        if broken {
+            game.messages.add(
+                format!("Your {} breaks!", game.inventory[id].display_name(game)),
+                ORANGE,
+            );
+        }
+    }
+}
 
-    let max_items = from_dungeon_level(
-        &[
-            Transition { level: 0, value: 1 },
-            Transition { level: 3, value: 2 },
-            Transition { level: 7, value: 4 },
-            Transition { level: 9, value: 5 },
-        ],
-        level,
-    );
+fn remove_broken_equipment(game: &mut Game) {
+    game.inventory
+        .retain(|item| item.equipment.is_none_or(|e| e.durability > 0));
+}
+
+fn item_chances(level: u32) -> Vec<rand::distributions::Weighted<Item>> {
+    use rand::distributions::Weighted;
 
-    let item_chances = &mut [
+    vec![
         Weighted {
             weight: from_dungeon_level(&[Transition { level: 4, value: 5 }], level),
             item: Item::Sword,
@@ -310,6 +799,30 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             weight: from_dungeon_level(&[Transition { level: 6, value: 5 }], level),
             item: Item::Helmet,
         },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 5, value: 4 }], level),
+            item: Item::Greatsword,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 4 }], level),
+            item: Item::Ring,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 4, value: 3 }], level),
+            item: Item::Amulet,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 5 }], level),
+            item: Item::Boots,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 5 }], level),
+            item: Item::Cloak,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 5 }], level),
+            item: Item::Gloves,
+        },
         Weighted {
             weight: from_dungeon_level(
                 &[Transition {
@@ -318,7 +831,7 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
                 }],
                 level,
             ),
-            item: Item::FireballStaff { range: 0 },
+            item: Item::Staff,
         },
         Weighted {
             weight: from_dungeon_level(
@@ -360,31 +873,129 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             weight: 35,
             item: Item::Heal { amount: 0 },
         },
+        Weighted {
+            weight: 20,
+            item: Item::Food { nutrition: 0 },
+        },
         Weighted {
             weight: from_dungeon_level(
                 &[Transition {
-                    level: 4,
-                    value: 25,
+                    level: 1,
+                    value: 15,
                 }],
                 level,
             ),
-            item: Item::Lightning { damage: 0 },
+            item: Item::Identify,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 8 }], level),
+            item: Item::Repair,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 6 }], level),
+            item: Item::RemoveCurse,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 4 }], level),
+            item: Item::HolyWater,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 4, value: 5 }], level),
+            item: Item::Recharge,
         },
         Weighted {
             weight: from_dungeon_level(
-                &[Transition {
-                    level: 6,
-                    value: 25,
-                }],
+                &[
+                    Transition { level: 1, value: 6 },
+                    Transition { level: 4, value: 4 },
+                ],
                 level,
             ),
-            item: Item::Fireball {
-                damage: 0,
-                charges: 0,
-            },
+            item: Item::RemoveFog,
         },
         Weighted {
-            weight: from_dungeon_level(
+            weight: from_dungeon_level(&[Transition { level: 2, value: 6 }], level),
+            item: Item::DetectMonsters,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 6 }], level),
+            item: Item::Teleport,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[
+                    Transition { level: 4, value: 4 },
+                    Transition { level: 7, value: 7 },
+                ],
+                level,
+            ),
+            item: Item::MagicMapping,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 6 }], level),
+            item: Item::Wand {
+                effect: WandEffect::Slow,
+                charges: 0,
+                recharged: 0,
+                charges_known: false,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 5 }], level),
+            item: Item::Wand {
+                effect: WandEffect::Digging,
+                charges: 0,
+                recharged: 0,
+                charges_known: false,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 3, value: 6 }], level),
+            item: Item::Wand {
+                effect: WandEffect::Lightning,
+                charges: 0,
+                recharged: 0,
+                charges_known: false,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 4, value: 5 }], level),
+            item: Item::Wand {
+                effect: WandEffect::Teleport,
+                charges: 0,
+                recharged: 0,
+                charges_known: false,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 2, value: 4 }], level),
+            item: Item::Bag,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition {
+                    level: 4,
+                    value: 25,
+                }],
+                level,
+            ),
+            item: Item::Lightning { damage: 0 },
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition {
+                    level: 6,
+                    value: 25,
+                }],
+                level,
+            ),
+            item: Item::Fireball {
+                damage: 0,
+                charges: 0,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(
                 &[Transition {
                     level: 2,
                     value: 10,
@@ -393,7 +1004,210 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
             ),
             item: Item::Confuse,
         },
+        Weighted {
+            weight: 10,
+            item: Item::SpellBook {
+                spell: Spell::MagicMissile,
+            },
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition {
+                    level: 2,
+                    value: 10,
+                }],
+                level,
+            ),
+            item: Item::Bow,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition {
+                    level: 2,
+                    value: 15,
+                }],
+                level,
+            ),
+            item: Item::Arrows,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[Transition { level: 6, value: 8 }], level),
+            item: Item::Crossbow,
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[Transition {
+                    level: 6,
+                    value: 12,
+                }],
+                level,
+            ),
+            item: Item::Bolts,
+        },
+        Weighted {
+            weight: 15,
+            item: Item::Gold,
+        },
+    ]
+}
+
+fn make_monster(kind: &str, x: i32, y: i32) -> Object {
+    let mut monster = match kind {
+        "orc" => {
+            let mut orc = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
+            orc.fighter = Some(Fighter {
+                base_max_hp: 20,
+                hp: 20,
+                base_defense: 0,
+                base_power: 4,
+                base_max_mana: 0,
+                base_spellcast_modifier: 0,
+                base_accuracy: 0,
+                base_evasion: 0,
+                mana: 0,
+                xp: 35,
+                hp_regen_progress: 0.0,
+                mana_regen_progress: 0.0,
+                attributes: Attributes::average(),
+                on_death: DeathCallback::Monster,
+            });
+            orc.ai = Some(Ai::Basic);
+            orc
+        }
+        "troll" => {
+            let mut troll = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
+            troll.fighter = Some(Fighter {
+                base_max_hp: 30,
+                hp: 30,
+                base_defense: 2,
+                base_power: 8,
+                base_max_mana: 0,
+                base_spellcast_modifier: 0,
+                base_accuracy: 0,
+                base_evasion: -1,
+                mana: 0,
+                xp: 100,
+                hp_regen_progress: 0.0,
+                mana_regen_progress: 0.0,
+                attributes: Attributes::average(),
+                on_death: DeathCallback::Monster,
+            });
+            troll.ai = Some(Ai::Basic);
+
+            troll
+        }
+        "archer" => {
+            let mut archer = Object::new(x, y, 'a', "archer", DARKER_GREEN, true);
+            archer.fighter = Some(Fighter {
+                base_max_hp: 15,
+                hp: 15,
+                base_defense: 1,
+                base_power: 6,
+                base_max_mana: 0,
+                base_spellcast_modifier: 0,
+                base_accuracy: 2,
+                base_evasion: 1,
+                mana: 0,
+                xp: 50,
+                hp_regen_progress: 0.0,
+                mana_regen_progress: 0.0,
+                attributes: Attributes::average(),
+                on_death: DeathCallback::MonsterArcher,
+            });
+            archer.ai = Some(Ai::Archer { range: 8 });
+
+            archer
+        }
+        _ => unreachable!(),
+    };
+
+    monster.alive = true;
+
+    monster
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
+    use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
+
+    let max_monsters = from_dungeon_level(
+        &[
+            Transition { level: 0, value: 2 },
+            Transition { level: 4, value: 3 },
+            Transition { level: 6, value: 5 },
+            Transition { level: 9, value: 8 },
+            Transition {
+                level: 14,
+                value: 13,
+            },
+        ],
+        level,
+    );
+
+    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
+
+    let monster_chances = &mut [
+        Weighted {
+            weight: from_dungeon_level(
+                &[
+                    Transition {
+                        level: 3,
+                        value: 15,
+                    },
+                    Transition {
+                        level: 5,
+                        value: 30,
+                    },
+                    Transition {
+                        level: 7,
+                        value: 60,
+                    },
+                    Transition {
+                        level: 12,
+                        value: 100,
+                    },
+                ],
+                level,
+            ),
+            item: "troll",
+        },
+        Weighted {
+            weight: from_dungeon_level(
+                &[
+                    Transition {
+                        level: 4,
+                        value: 30,
+                    },
+                    Transition {
+                        level: 6,
+                        value: 80,
+                    },
+                    Transition {
+                        level: 8,
+                        value: 90,
+                    },
+                ],
+                level,
+            ),
+            item: "archer",
+        },
+        Weighted {
+            weight: 80,
+            item: "orc",
+        },
     ];
+    let monster_choice = WeightedChoice::new(monster_chances);
+
+    let max_items = from_dungeon_level(
+        &[
+            Transition { level: 0, value: 1 },
+            Transition { level: 3, value: 2 },
+            Transition { level: 7, value: 4 },
+            Transition { level: 9, value: 5 },
+        ],
+        level,
+    );
+
+    let item_chances = &mut item_chances(level);
     let item_choice = WeightedChoice::new(item_chances);
 
     for _ in 0..num_monsters {
@@ -401,63 +1215,8 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
         let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
 
         if !is_blocked(x, y, map, objects) {
-            let mut monster = match monster_choice.ind_sample(&mut rand::thread_rng()) {
-                "orc" => {
-                    let mut orc = Object::new(x, y, 'o', "orc", DESATURATED_GREEN, true);
-                    orc.fighter = Some(Fighter {
-                        base_max_hp: 20,
-                        hp: 20,
-                        base_defense: 0,
-                        base_power: 4,
-                        base_max_mana: 0,
-                        base_spellcast_modifier: 0,
-                        mana: 0,
-                        xp: 35,
-                        on_death: DeathCallback::Monster,
-                    });
-                    orc.ai = Some(Ai::Basic);
-                    orc
-                }
-                "troll" => {
-                    let mut troll = Object::new(x, y, 'T', "troll", DARKER_GREEN, true);
-                    troll.fighter = Some(Fighter {
-                        base_max_hp: 30,
-                        hp: 30,
-                        base_defense: 2,
-                        base_power: 8,
-                        base_max_mana: 0,
-                        base_spellcast_modifier: 0,
-                        mana: 0,
-                        xp: 100,
-                        on_death: DeathCallback::Monster,
-                    });
-                    troll.ai = Some(Ai::Basic);
-
-                    troll
-                }
-                "archer" => {
-                    let mut archer = Object::new(x, y, 'a', "archer", DARKER_GREEN, true);
-                    archer.fighter = Some(Fighter {
-                        base_max_hp: 15,
-                        hp: 15,
-                        base_defense: 1,
-                        base_power: 6,
-                        base_max_mana: 0,
-                        base_spellcast_modifier: 0,
-                        mana: 0,
-                        xp: 50,
-                        on_death: DeathCallback::MonsterArcher,
-                    });
-                    archer.ai = Some(Ai::Archer { range: 8 });
-
-                    archer
-                }
-                _ => unreachable!(),
-            };
-
-            monster.alive = true;
-
-            objects.push(monster);
+            let kind = monster_choice.ind_sample(&mut rand::thread_rng());
+            objects.push(make_monster(kind, x, y));
         }
     }
 
@@ -468,946 +1227,4458 @@ fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, level: u32) {
         let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
 
         if !is_blocked(x, y, map, objects) {
-            objects.push(match item_choice.ind_sample(&mut rand::thread_rng()) {
-                Item::Lightning { .. } => {
-                    let mut object =
-                        Object::new(x, y, '#', "scroll of lightning", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Lightning {
-                        damage: LIGHTNING_DAMAGE,
-                    });
-                    object
-                }
-                Item::FireballStaff { .. } => {
-                    let mut object = Object::new(x, y, '|', "fireball staff", ORANGE, false);
-                    object.item = Some(Item::FireballStaff { range: 8 });
-                    object.equipment = Some(Equipment {
-                        equipped: false,
-                        slot: Slot::RightHand,
-                        power_bonus: 0,
-                        defense_bonus: 0,
-                        max_hp_bonus: 0,
-                        mana_usage: 5,
-                        spellcast_bonus: from_dungeon_level(
-                            &[
-                                Transition { level: 0, value: 8 },
-                                Transition {
-                                    level: 6,
-                                    value: 13,
-                                },
-                            ],
-                            level,
-                        ) as i32,
-                        max_mana_bonus: from_dungeon_level(
-                            &[
-                                Transition {
-                                    level: 0,
-                                    value: 10,
-                                },
-                                Transition {
-                                    level: 10,
-                                    value: 20,
-                                },
-                            ],
-                            level,
-                        ) as i32,
-                    });
-                    object
-                }
-                Item::Fireball { .. } => {
-                    let mut object =
-                        Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Fireball {
-                        charges: FIREBALL_CHARGES,
-                        damage: FIREBALL_DAMAGE,
-                    });
-                    object
-                }
-                Item::Confuse => {
-                    let mut object =
-                        Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
-                    object.item = Some(Item::Confuse);
-                    object
-                }
-                Item::Heal { .. } => {
-                    let mut object = Object::new(x, y, '!', "healing potion", DARKER_RED, false);
-                    object.item = Some(Item::Heal {
-                        amount: HEAL_AMOUNT,
-                    });
-                    object
-                }
-                Item::ManaPotion { .. } => {
-                    let mut object = Object::new(x, y, '!', "mana potion", VIOLET, false);
-                    object.item = Some(Item::ManaPotion);
-                    object
-                }
-                Item::Sword => {
-                    let mut object = Object::new(x, y, '/', "sword", SKY, false);
-                    object.item = Some(Item::Sword);
-                    object.equipment = Some(Equipment {
-                        mana_usage: 0,
-                        equipped: false,
-                        slot: Slot::RightHand,
-                        power_bonus: 3,
-                        defense_bonus: 0,
-                        max_hp_bonus: 0,
-                        max_mana_bonus: 0,
-                        spellcast_bonus: 0,
-                    });
-                    object
-                }
-                Item::Shield => {
-                    let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
-                    object.item = Some(Item::Shield);
-                    object.equipment = Some(Equipment {
-                        mana_usage: 0,
-                        equipped: false,
-                        slot: Slot::LeftHand,
-                        power_bonus: 0,
-                        defense_bonus: 1,
-                        max_hp_bonus: 0,
-                        max_mana_bonus: 0,
-                        spellcast_bonus: 0,
-                    });
-                    object
-                }
-                Item::Helmet => {
-                    let mut object = Object::new(x, y, '^', "helmet", YELLOW, false);
-                    object.item = Some(Item::Shield);
-                    object.equipment = Some(Equipment {
-                        mana_usage: 0,
-                        equipped: false,
-                        slot: Slot::Head,
-                        power_bonus: 0,
-                        defense_bonus: 2,
-                        max_hp_bonus: 0,
-                        max_mana_bonus: 0,
-                        spellcast_bonus: 0,
-                    });
-                    object
-                }
-                Item::BodyArmor => {
-                    let mut object = Object::new(x, y, '=', "body armor", GREEN, false);
-                    object.item = Some(Item::BodyArmor);
-                    object.equipment = Some(Equipment {
-                        mana_usage: 0,
-                        equipped: false,
-                        slot: Slot::Body,
-                        power_bonus: 0,
-                        defense_bonus: 4,
-                        max_hp_bonus: 0,
-                        max_mana_bonus: 0,
-                        spellcast_bonus: 0,
-                    });
-                    object
-                }
-            });
+            let kind = item_choice.ind_sample(&mut rand::thread_rng());
+            objects.push(make_item(kind, x, y, level));
         }
     }
-}
 
-type Map = Vec<Vec<Tile>>;
+    if rand::thread_rng().gen::<f32>() < CHEST_CHANCE {
+        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
 
-#[derive(Serialize, Deserialize)]
-struct Game {
-    map: Map,
-    messages: Messages,
-    inventory: Vec<Object>,
-    dungeon_level: u32,
+        // the stairs may end up in the middle of the room, where a chest would block them
+        if (x, y) != room.center() && !objects.iter().any(|object| object.pos() == (x, y)) {
+            let num_contents = rand::thread_rng().gen_range(1, CHEST_MAX_ITEMS + 1);
+            let contents = (0..num_contents)
+                .map(|_| make_item(item_choice.ind_sample(&mut rand::thread_rng()), x, y, level))
+                .collect();
+            let mut chest = Object::new(x, y, '=', "chest", DARK_AMBER, true);
+            chest.always_visible = true;
+            chest.container = Some(Container {
+                contents,
+                capacity: CHEST_CAPACITY,
+                locked: rand::thread_rng().gen::<f32>() < CHEST_LOCKED_CHANCE,
+                trapped: rand::thread_rng().gen::<f32>() < CHEST_TRAPPED_CHANCE,
+            });
+            objects.push(chest);
+        }
+    }
 }
 
-fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
-    // fill with "unblocked" tiles
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
-
-    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
-    objects.truncate(1);
-
-    let mut rooms = vec![];
-
-    for _ in 0..MAX_ROOMS {
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
-
-        let new_room = Rect::new(x, y, w, h);
-
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
-
-        if !failed {
-            create_room(new_room, &mut map);
-            let (new_x, new_y) = new_room.center();
+// creates an item of the given kind, possibly with affixes and a curse or blessing
+fn make_item(kind: Item, x: i32, y: i32, level: u32) -> Object {
+    let mut item = plain_item(kind, x, y, level);
+    add_affixes(&mut item, level);
+    let ammunition = item.equipment.is_some_and(|e| e.slot == Slot::Ammunition);
+    if !ammunition && item.item != Some(Item::HolyWater) && item.item != Some(Item::Gold) {
+        item.blessing = Blessing::random();
+    }
+    item
+}
 
-            if rooms.is_empty() {
-                objects[PLAYER].set_pos(new_x, new_y);
-            } else {
+// creates an item of the given kind, uncursed and without affixes
+fn plain_item(kind: Item, x: i32, y: i32, level: u32) -> Object {
+    match kind {
+        Item::Lightning { .. } => {
+            let mut object = Object::new(x, y, '#', "scroll of lightning", LIGHT_YELLOW, false);
+            object.item = Some(Item::Lightning {
+                damage: LIGHTNING_DAMAGE,
+            });
+            object
+        }
+        Item::Staff => {
+            let mut object = Object::new(x, y, '|', "staff", ORANGE, false);
+            object.item = Some(Item::Staff);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                identified: false,
+                slot: Slot::RightHand,
+                power_bonus: 0,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                range: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Intelligence, 12)),
+                prefix: None,
+                suffix: None,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 50,
+                spellcast_bonus: from_dungeon_level(
+                    &[
+                        Transition { level: 0, value: 8 },
+                        Transition {
+                            level: 6,
+                            value: 13,
+                        },
+                    ],
+                    level,
+                ) as i32,
+                max_mana_bonus: from_dungeon_level(
+                    &[
+                        Transition {
+                            level: 0,
+                            value: 10,
+                        },
+                        Transition {
+                            level: 10,
+                            value: 20,
+                        },
+                    ],
+                    level,
+                ) as i32,
+            });
+            object
+        }
+        Item::Fireball { .. } => {
+            let mut object = Object::new(x, y, '#', "scroll of fireball", LIGHT_YELLOW, false);
+            object.item = Some(Item::Fireball {
+                charges: FIREBALL_CHARGES,
+                damage: FIREBALL_DAMAGE,
+            });
+            object
+        }
+        Item::SpellBook { .. } => {
+            let spells: Vec<Spell> = Spell::ALL
+                .iter()
+                .cloned()
+                .filter(|spell| spell.min_level() <= level)
+                .collect();
+            let spell = *rand::thread_rng().choose(&spells).unwrap();
+            let mut object = Object::new(
+                x,
+                y,
+                '+',
+                &format!("book of {}", spell),
+                LIGHT_VIOLET,
+                false,
+            );
+            object.item = Some(Item::SpellBook { spell });
+            object
+        }
+        Item::Identify => {
+            let mut object = Object::new(x, y, '#', "scroll of identify", LIGHT_YELLOW, false);
+            object.item = Some(Item::Identify);
+            object
+        }
+        Item::Repair => {
+            let mut object = Object::new(x, y, '#', "scroll of repair", LIGHT_YELLOW, false);
+            object.item = Some(Item::Repair);
+            object
+        }
+        Item::Ingredient { kind } => {
+            let mut object = Object::new(x, y, ',', &kind.to_string(), LIGHT_SEPIA, false);
+            object.item = Some(Item::Ingredient { kind });
+            object
+        }
+        Item::Recharge => {
+            let mut object = Object::new(x, y, '#', "scroll of recharging", LIGHT_YELLOW, false);
+            object.item = Some(Item::Recharge);
+            object
+        }
+        Item::MagicMapping => {
+            let mut object = Object::new(x, y, '#', "scroll of magic mapping", LIGHT_YELLOW, false);
+            object.item = Some(Item::MagicMapping);
+            object
+        }
+        Item::Teleport => {
+            let mut object = Object::new(x, y, '#', "scroll of teleportation", LIGHT_YELLOW, false);
+            object.item = Some(Item::Teleport);
+            object
+        }
+        Item::DetectMonsters => {
+            let mut object =
+                Object::new(x, y, '#', "scroll of detect monsters", LIGHT_YELLOW, false);
+            object.item = Some(Item::DetectMonsters);
+            object
+        }
+        Item::RemoveFog => {
+            let mut object = Object::new(x, y, '#', "scroll of remove fog", LIGHT_YELLOW, false);
+            object.item = Some(Item::RemoveFog);
+            object
+        }
+        Item::Wand { effect, .. } => {
+            let mut object = Object::new(
+                x,
+                y,
+                '-',
+                &format!("wand of {}", effect),
+                LIGHT_SEPIA,
+                false,
+            );
+            object.item = Some(Item::Wand {
+                effect,
+                charges: rand::thread_rng().gen_range(WAND_MIN_CHARGES, WAND_MAX_CHARGES + 1),
+                recharged: 0,
+                charges_known: false,
+            });
+            object
+        }
+        Item::RemoveCurse => {
+            let mut object = Object::new(x, y, '#', "scroll of remove curse", LIGHT_YELLOW, false);
+            object.item = Some(Item::RemoveCurse);
+            object
+        }
+        Item::HolyWater => {
+            let mut object = Object::new(x, y, '!', "holy water", LIGHTEST_BLUE, false);
+            object.item = Some(Item::HolyWater);
+            object
+        }
+        Item::Gold => make_gold(
+            x,
+            y,
+            rand::thread_rng().gen_range(GOLD_MIN, GOLD_MAX + 1) * (level as i32 + 1),
+        ),
+        Item::Relic => {
+            let mut object = Object::new(x, y, '?', "relic", LIGHTEST_GREY, false);
+            object.item = Some(Item::Relic);
+            object.always_visible = true;
+            object
+        }
+        Item::Bag => {
+            let mut object = Object::new(x, y, '(', "bag", DARK_SEPIA, false);
+            object.item = Some(Item::Bag);
+            object.container = Some(Container {
+                contents: vec![],
+                capacity: BAG_CAPACITY,
+                locked: false,
+                trapped: false,
+            });
+            object
+        }
+        Item::Confuse => {
+            let mut object = Object::new(x, y, '#', "scroll of confusion", LIGHT_YELLOW, false);
+            object.item = Some(Item::Confuse);
+            object
+        }
+        Item::Heal { .. } => {
+            let mut object = Object::new(x, y, '!', "healing potion", DARKER_RED, false);
+            object.item = Some(Item::Heal {
+                amount: HEAL_AMOUNT,
+            });
+            object
+        }
+        // corpses are normally left behind by dying monsters, any other is of no creature in particular
+        Item::Corpse { .. } => {
+            let mut object = Object::new(x, y, '%', "remains of a creature", DARK_RED, false);
+            object.item = Some(kind);
+            object
+        }
+        Item::Food { .. } => {
+            let mut object = Object::new(x, y, '%', "ration", LIGHT_SEPIA, false);
+            object.item = Some(Item::Food {
+                nutrition: RATION_NUTRITION,
+            });
+            object
+        }
+        Item::ManaPotion { .. } => {
+            let mut object = Object::new(x, y, '!', "mana potion", VIOLET, false);
+            object.item = Some(Item::ManaPotion);
+            object
+        }
+        Item::Sword => {
+            let mut object = Object::new(x, y, '/', "sword", SKY, false);
+            object.item = Some(Item::Sword);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::RightHand,
+                power_bonus: 3,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 1,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Shield => {
+            let mut object = Object::new(x, y, '[', "shield", DARKER_ORANGE, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::LeftHand,
+                power_bonus: 0,
+                defense_bonus: 1,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 1,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Strength, 11)),
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Helmet => {
+            let mut object = Object::new(x, y, '^', "helmet", YELLOW, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Head,
+                power_bonus: 0,
+                defense_bonus: 2,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::BodyArmor => {
+            let mut object = Object::new(x, y, '=', "body armor", GREEN, false);
+            object.item = Some(Item::BodyArmor);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Body,
+                power_bonus: 0,
+                defense_bonus: 4,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: -1,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Strength, 13)),
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Greatsword => {
+            let mut object = Object::new(x, y, '/', "greatsword", LIGHT_SKY, false);
+            object.item = Some(Item::Greatsword);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::RightHand,
+                power_bonus: 6,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 3,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: true,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Strength, 14)),
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Ring => {
+            let mut object = Object::new(x, y, '*', "ring", GOLD, false);
+            object.item = Some(Item::Ring);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::LeftRing,
+                power_bonus: 0,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 1,
+                evasion_bonus: 1,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Amulet => {
+            let mut object = Object::new(x, y, '"', "amulet", AMBER, false);
+            object.item = Some(Item::Amulet);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Neck,
+                power_bonus: 0,
+                defense_bonus: 0,
+                max_hp_bonus: 5,
+                max_mana_bonus: 5,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Boots => {
+            let mut object = Object::new(x, y, ']', "boots", SEPIA, false);
+            object.item = Some(Item::Boots);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Feet,
+                power_bonus: 0,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 2,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Cloak => {
+            let mut object = Object::new(x, y, '(', "cloak", DARK_VIOLET, false);
+            object.item = Some(Item::Cloak);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Back,
+                power_bonus: 0,
+                defense_bonus: 1,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 1,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Gloves => {
+            let mut object = Object::new(x, y, ']', "gloves", LIGHT_SEPIA, false);
+            object.item = Some(Item::Gloves);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Hands,
+                power_bonus: 0,
+                defense_bonus: 1,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 1,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Bow => {
+            let mut object = Object::new(x, y, '}', "bow", LIGHT_SEPIA, false);
+            object.item = Some(Item::Bow);
+            object.equipment = Some(Equipment {
+                range: 8,
+                equipped: false,
+                identified: false,
+                slot: Slot::RightHand,
+                power_bonus: 2,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: true,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Dexterity, 12)),
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Crossbow => {
+            let mut object = Object::new(x, y, '}', "crossbow", SEPIA, false);
+            object.item = Some(Item::Crossbow);
+            object.equipment = Some(Equipment {
+                range: 10,
+                equipped: false,
+                identified: false,
+                slot: Slot::RightHand,
+                power_bonus: 5,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 1,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: true,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: Some((Attribute::Strength, 12)),
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Arrows => {
+            let mut object = Object::new(x, y, '(', "arrows", LIGHT_SEPIA, false);
+            object.item = Some(Item::Arrows);
+            object.count = rand::thread_rng().gen_range(5, 16);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Ammunition,
+                power_bonus: 1,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+        Item::Bolts => {
+            let mut object = Object::new(x, y, '(', "bolts", SEPIA, false);
+            object.item = Some(Item::Bolts);
+            object.count = rand::thread_rng().gen_range(5, 16);
+            object.equipment = Some(Equipment {
+                range: 0,
+                equipped: false,
+                identified: false,
+                slot: Slot::Ammunition,
+                power_bonus: 2,
+                defense_bonus: 0,
+                max_hp_bonus: 0,
+                max_mana_bonus: 0,
+                spellcast_bonus: 0,
+                accuracy_bonus: 0,
+                evasion_bonus: 0,
+                critical_bonus: 0,
+                hp_regen_bonus: 0,
+                mana_regen_bonus: 0,
+                on_hit: None,
+                two_handed: false,
+                durability: EQUIPMENT_DURABILITY,
+                max_durability: EQUIPMENT_DURABILITY,
+                requirement: None,
+                prefix: None,
+                suffix: None,
+            });
+            object
+        }
+    }
+}
+
+type Map = Vec<Vec<Tile>>;
+
+#[derive(Serialize, Deserialize)]
+struct Game {
+    map: Map,
+    messages: Messages,
+    inventory: Vec<Object>,
+    #[serde(default)]
+    spellbook: Vec<Spell>,
+    #[serde(default = "start_nutrition")]
+    nutrition: i32,
+    #[serde(default)]
+    appearances: HashMap<String, String>,
+    #[serde(default)]
+    identified: Vec<String>,
+    dungeon_level: u32,
+    #[serde(default)]
+    class: Class,
+    #[serde(default)]
+    perks: Vec<Perk>,
+    #[serde(default)]
+    gold: i32,
+    // turns left in which monsters are shown outside the field of view
+    #[serde(default)]
+    detect_monsters: i32,
+    // set by conversations, so NPCs remember what was said
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default)]
+    quests: Vec<Quest>,
+    // objects created where the objects list can't be reached, added to it after the player's turn
+    #[serde(skip)]
+    drops: Vec<Object>,
+}
+
+impl Game {
+    pub fn has_perk(&self, perk: Perk) -> bool {
+        self.perks.contains(&perk)
+    }
+
+    pub fn is_identified(&self, name: &str) -> bool {
+        !self.appearances.contains_key(name) || self.identified.iter().any(|n| n == name)
+    }
+
+    pub fn identify(&mut self, name: &str) {
+        if !self.is_identified(name) {
+            let appearance = self.appearances[name].clone();
+            self.identified.push(name.into());
+            self.messages
+                .add(format!("The {} was a {}", appearance, name), LIGHT_VIOLET);
+        }
+    }
+}
+
+// potions, scrolls and wands get new appearances every game, so they have to be identified again
+fn random_appearances() -> HashMap<String, String> {
+    let mut potions = POTION_APPEARANCES.to_vec();
+    let mut labels = SCROLL_LABELS.to_vec();
+    let mut materials = WAND_MATERIALS.to_vec();
+    rand::thread_rng().shuffle(&mut potions);
+    rand::thread_rng().shuffle(&mut labels);
+    rand::thread_rng().shuffle(&mut materials);
+
+    let potions = POTION_KINDS
+        .iter()
+        .zip(potions)
+        .map(|(kind, color)| (kind.to_string(), format!("{} potion", color)));
+    let scrolls = SCROLL_KINDS
+        .iter()
+        .zip(labels)
+        .map(|(kind, label)| (kind.to_string(), format!("scroll labeled {}", label)));
+
+    let wands = WAND_KINDS
+        .iter()
+        .zip(materials)
+        .map(|(kind, material)| (kind.to_string(), format!("{} wand", material)));
+
+    potions.chain(scrolls).chain(wands).collect()
+}
+
+fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
+    // fill with "unblocked" tiles
+    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    objects.truncate(1);
+
+    let mut rooms = vec![];
+
+    for _ in 0..MAX_ROOMS {
+        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+
+        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
+        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+
+        let new_room = Rect::new(x, y, w, h);
+
+        let failed = rooms
+            .iter()
+            .any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            create_room(new_room, &mut map);
+            let (new_x, new_y) = new_room.center();
+
+            if rooms.is_empty() {
+                objects[PLAYER].set_pos(new_x, new_y);
+            } else {
                 place_objects(new_room, &map, objects, level);
                 let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
 
-                if rand::random() {
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                }
+                if rand::random() {
+                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
+                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
+                } else {
+                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
+                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
+                }
+            }
+
+            rooms.push(new_room);
+        }
+    }
+
+    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
+    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+
+    if level > 0 && rooms.len() > 2 && rand::thread_rng().gen::<f32>() < SHOP_CHANCE {
+        let room = rooms[rand::thread_rng().gen_range(1, rooms.len() - 1)];
+        make_shop(room, objects, level);
+    }
+
+    if rooms.len() > 1 && rand::thread_rng().gen::<f32>() < ANVIL_CHANCE {
+        let room = rooms[rand::thread_rng().gen_range(1, rooms.len())];
+        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+        if !objects.iter().any(|object| object.pos() == (x, y)) {
+            let mut anvil = Object::new(x, y, '&', "anvil", LIGHT_GREY, true);
+            anvil.always_visible = true;
+            anvil.anvil = Some(Anvil { cracked: false });
+            objects.push(anvil);
+        }
+    }
+
+    map
+}
+
+// turns a room into a shop, clearing out whatever was placed there for the shopkeeper's stock
+fn make_shop(room: Rect, objects: &mut Vec<Object>, level: u32) {
+    use rand::distributions::{IndependentSample, WeightedChoice};
+
+    let (x1, y1, x2, y2) = (room.x1 + 1, room.y1 + 1, room.x2 - 1, room.y2 - 1);
+    objects.retain(|object| {
+        object.name == "player" || !(x1..=x2).contains(&object.x) || !(y1..=y2).contains(&object.y)
+    });
+
+    // the stock is laid out away from the walls, leaving an aisle to walk around it
+    let item_chances = &mut item_chances(level);
+    let item_choice = WeightedChoice::new(item_chances);
+    for x in (x1 + 1)..x2 {
+        for y in (y1 + 1)..y2 {
+            if rand::thread_rng().gen::<f32>() < SHOP_ITEM_CHANCE {
+                let kind = item_choice.ind_sample(&mut rand::thread_rng());
+                if kind != Item::Gold {
+                    let mut item = make_item(kind, x, y, level);
+                    item.for_sale = true;
+                    objects.push(item);
+                }
+            }
+        }
+    }
+
+    let mut shopkeeper = Object::new(x1, y1, '@', "shopkeeper", LIGHT_YELLOW, true);
+    shopkeeper.fighter = Some(Fighter {
+        base_max_hp: 80,
+        hp: 80,
+        base_defense: 4,
+        base_power: 12,
+        base_max_mana: 0,
+        base_spellcast_modifier: 0,
+        base_accuracy: 3,
+        base_evasion: 2,
+        mana: 0,
+        xp: 300,
+        hp_regen_progress: 0.0,
+        mana_regen_progress: 0.0,
+        attributes: Attributes::average(),
+        on_death: DeathCallback::Monster,
+    });
+    shopkeeper.ai = Some(Ai::Shopkeeper { x1, y1, x2, y2 });
+    shopkeeper.alive = true;
+    objects.push(shopkeeper);
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &Vec<Object>, fov_recompute: bool) {
+    if fov_recompute {
+        let player = &objects[PLAYER];
+        tcod.fov
+            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    }
+
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let visible = tcod.fov.is_in_fov(x, y);
+            let wall = game.map[x as usize][y as usize].block_sight;
+            let color = match (visible, wall) {
+                (false, true) => COLOR_DARK_WALL,
+                (false, false) => COLOR_DARK_GROUND,
+                (true, true) => COLOR_LIGHT_WALL,
+                (true, false) => COLOR_LIGHT_GROUND,
+            };
+
+            let explored = &mut game.map[x as usize][y as usize].explored;
+            if visible {
+                *explored = true;
+            }
+            if *explored {
+                tcod.con
+                    .set_char_background(x, y, color, BackgroundFlag::Set);
+            }
+        }
+    }
+
+    let mut to_draw: Vec<_> = objects
+        .iter()
+        .filter(|o| {
+            tcod.fov.is_in_fov(o.x, o.y)
+                || (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
+                || (game.detect_monsters > 0 && o.fighter.is_some() && o.ai.is_some())
+        })
+        .collect();
+
+    to_draw.sort_by(|o1, o2| o1.blocks.cmp(&o2.blocks));
+
+    for object in &to_draw {
+        object.draw(&mut tcod.con);
+    }
+
+    blit(
+        &tcod.con,
+        (0, 0),
+        (MAP_WIDTH, MAP_HEIGHT),
+        &mut tcod.root,
+        (0, 0),
+        1.0,
+        1.0,
+    );
+
+    tcod.panel.set_default_background(BLACK);
+    tcod.panel.clear();
+
+    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    let max_hp = objects[PLAYER].max_hp(game);
+
+    render_bar(
+        &mut tcod.panel,
+        1,
+        1,
+        BAR_WIDTH,
+        "HP",
+        hp,
+        max_hp,
+        LIGHT_RED,
+        DARKER_RED,
+    );
+
+    let mana = objects[PLAYER].fighter.map_or(0, |f| f.mana);
+    let max_mana = objects[PLAYER].max_mana(game);
+
+    render_bar(
+        &mut tcod.panel,
+        1,
+        2,
+        BAR_WIDTH,
+        "MANA",
+        mana,
+        max_mana,
+        LIGHT_BLUE,
+        DARKER_BLUE,
+    );
+
+    tcod.panel.print_ex(
+        1,
+        3,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Dungeon level: {}", game.dungeon_level),
+    );
+
+    let hunger = Hunger::from_nutrition(game.nutrition);
+    if hunger != Hunger::NotHungry {
+        tcod.panel.set_default_foreground(hunger.color());
+        tcod.panel.print_ex(
+            1,
+            4,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            hunger.to_string(),
+        );
+        tcod.panel.set_default_foreground(WHITE);
+    }
+
+    tcod.panel.set_default_foreground(GOLD);
+    tcod.panel.print_ex(
+        1,
+        5,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Gold: {}", game.gold),
+    );
+    tcod.panel.set_default_foreground(WHITE);
+
+    let mut y = MSG_HEIGHT as i32;
+    for &(ref msg, color) in game.messages.iter().rev() {
+        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        y -= msg_height;
+        if y <= 0 {
+            break;
+        }
+
+        tcod.panel.set_default_foreground(color);
+        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    }
+
+    tcod.panel.set_default_foreground(LIGHT_GREY);
+    tcod.panel.print_ex(
+        1,
+        0,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        get_names_under_mouse(tcod.mouse, objects, &tcod.fov, game),
+    );
+
+    blit(
+        &tcod.panel,
+        (0, 0),
+        (SCREEN_WIDTH, PANEL_HEIGHT),
+        &mut tcod.root,
+        (0, PANEL_Y),
+        1.0,
+        1.0,
+    );
+}
+
+fn render_bar(
+    panel: &mut Offscreen,
+    x: i32,
+    y: i32,
+    total_width: i32,
+    name: &str,
+    value: i32,
+    maximum: i32,
+    bar_color: Color,
+    back_color: Color,
+) {
+    let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
+
+    panel.set_default_background(back_color);
+    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+
+    panel.set_default_background(bar_color);
+    if bar_width > 0 {
+        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+    }
+
+    panel.set_default_foreground(WHITE);
+    panel.print_ex(
+        x + total_width / 2,
+        y,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        &format!("{}: {}/{}", name, value, maximum),
+    );
+}
+
+fn visible_targets(tcod: &Tcod, objects: &[Object], max_range: Option<f32>) -> Vec<usize> {
+    let player_pos = objects[PLAYER].pos();
+
+    let mut targets: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            let object = &objects[id];
+            id != PLAYER
+                && object.fighter.is_some()
+                && object.ai.as_ref().is_some_and(|ai| ai.is_hostile())
+                && tcod.fov.is_in_fov(object.x, object.y)
+                && max_range.is_none_or(|range| object.distance_to_point(player_pos) <= range)
+        })
+        .collect();
+
+    targets.sort_by(|&a, &b| {
+        let dist_a = objects[a].distance_to_point(player_pos);
+        let dist_b = objects[b].distance_to_point(player_pos);
+        dist_a.partial_cmp(&dist_b).unwrap()
+    });
+
+    targets
+}
+
+fn render_targeting(
+    tcod: &mut Tcod,
+    map: &Map,
+    from: (i32, i32),
+    cursor: (i32, i32),
+    radius: i32,
+    valid: bool,
+) {
+    if radius > 0 {
+        let blast = blast_map(cursor, radius, map);
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                let dx = x - cursor.0;
+                let dy = y - cursor.1;
+                let in_radius = ((dx.pow(2) + dy.pow(2)) as f32).sqrt() <= radius as f32;
+                if in_radius && blast.is_in_fov(x, y) && tcod.fov.is_in_fov(x, y) {
+                    tcod.root
+                        .set_char_background(x, y, COLOR_TARGET_AREA, BackgroundFlag::Set);
+                }
+            }
+        }
+    }
+
+    // the projectile flies until it hits a wall, everything behind is drawn darker
+    let mut blocked = false;
+    for (x, y) in tcod::line::Line::new(from, cursor) {
+        let color = if !valid || blocked {
+            COLOR_TARGET_BLOCKED
+        } else {
+            COLOR_TARGET_PATH
+        };
+        tcod.root
+            .set_char_background(x, y, color, BackgroundFlag::Set);
+        blocked = blocked || map[x as usize][y as usize].blocked;
+    }
+
+    tcod.root
+        .set_char_background(cursor.0, cursor.1, WHITE, BackgroundFlag::Set);
+}
+
+fn target_tile(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &Vec<Object>,
+    max_range: Option<f32>,
+    radius: i32,
+) -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::*;
+
+    game.messages.add(
+        "Move the cursor with the movement keys or the mouse, Tab cycles through enemies. \
+         Enter or left-click to confirm, Escape or right-click to cancel.",
+        LIGHT_CYAN,
+    );
+
+    let player_pos = objects[PLAYER].pos();
+    let targets = visible_targets(tcod, objects, max_range);
+    let mut next_target = 0;
+    let mut cursor = match targets.first() {
+        Some(&id) => {
+            next_target = 1;
+            objects[id].pos()
+        }
+        None => player_pos,
+    };
+
+    loop {
+        let valid = tcod.fov.is_in_fov(cursor.0, cursor.1)
+            && max_range.is_none_or(|range| objects[PLAYER].distance_to_point(cursor) <= range);
+
+        tcod.con.clear();
+        render_all(tcod, game, objects, false);
+        render_targeting(tcod, &game.map, player_pos, cursor, radius, valid);
+        tcod.root.flush();
+
+        let mut confirm = false;
+        tcod.key = Default::default();
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(m))) => {
+                tcod.mouse = m;
+                let (x, y) = (m.cx as i32, m.cy as i32);
+                if x < MAP_WIDTH && y < MAP_HEIGHT {
+                    if m.dcx != 0 || m.dcy != 0 || m.lbutton_pressed {
+                        cursor = (x, y);
+                    }
+                    confirm = m.lbutton_pressed;
+                }
+                if m.rbutton_pressed {
+                    return None;
+                }
+            }
+            Some((_, Event::Key(k))) => tcod.key = k,
+            _ => {}
+        }
+
+        let (dx, dy) = match (tcod.key.code, tcod.key.text()) {
+            (Up, _) | (Text, "k") => (0, -1),
+            (Down, _) | (Text, "j") => (0, 1),
+            (Left, _) | (Text, "h") => (-1, 0),
+            (Right, _) | (Text, "l") => (1, 0),
+            _ => (0, 0),
+        };
+        cursor = (
+            (cursor.0 + dx).clamp(0, MAP_WIDTH - 1),
+            (cursor.1 + dy).clamp(0, MAP_HEIGHT - 1),
+        );
+
+        match tcod.key.code {
+            Tab if !targets.is_empty() => {
+                cursor = objects[targets[next_target % targets.len()]].pos();
+                next_target += 1;
+            }
+            Enter => confirm = true,
+            Escape => return None,
+            _ => {}
+        }
+
+        if confirm {
+            let valid = tcod.fov.is_in_fov(cursor.0, cursor.1)
+                && max_range.is_none_or(|range| objects[PLAYER].distance_to_point(cursor) <= range);
+            if valid {
+                return Some(cursor);
+            }
+            game.messages.add("That target is out of range", WHITE);
+        }
+    }
+}
+
+fn target_monster(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &Vec<Object>,
+    max_range: Option<f32>,
+    msg: &str,
+) -> Option<usize> {
+    game.messages.add(msg, LIGHT_CYAN);
+    let (x, y) = target_tile(tcod, game, objects, max_range, 0)?;
+
+    objects.iter().enumerate().position(|(id, o)| {
+        id != PLAYER && o.fighter.is_some() && o.ai.is_some() && o.pos() == (x, y)
+    })
+}
+
+fn blast_map(center: (i32, i32), radius: i32, map: &Map) -> FovMap {
+    let mut blast = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            let tile = &map[x as usize][y as usize];
+            blast.set(x, y, !tile.block_sight, !tile.blocked);
+        }
+    }
+    blast.compute_fov(center.0, center.1, radius, FOV_LIGHT_WALLS, FOV_ALGO);
+    blast
+}
+
+// calls `effect` on every fighter within `radius` of `center` that the blast can reach
+fn area_effect<F>(
+    center: (i32, i32),
+    radius: i32,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    mut effect: F,
+) where
+    F: FnMut(usize, &mut Game, &mut Vec<Object>),
+{
+    let blast = blast_map(center, radius, &game.map);
+    let targets: Vec<usize> = objects
+        .iter()
+        .enumerate()
+        .filter(|(_, o)| {
+            o.fighter.is_some()
+                && blast.is_in_fov(o.x, o.y)
+                && o.distance_to_point(center) <= radius as f32
+        })
+        .map(|(id, _)| id)
+        .collect();
+
+    for id in targets {
+        effect(id, game, objects);
+    }
+}
+
+fn effect_bolt(
+    name: &str,
+    damage: i32,
+    range: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let target = target_monster(
+        tcod,
+        game,
+        objects,
+        Some(range as f32),
+        &format!("Choose target for {}", name),
+    );
+    if let Some(target) = target {
+        let (player, target) = mut_two(PLAYER, target, objects);
+        player.strike(target, game, damage as f32, false, format!("with {}", name));
+
+        UseResult::Used
+    } else {
+        game.messages.add("You need to choose a target", WHITE);
+        UseResult::Cancelled
+    }
+}
+
+fn effect_fireball(
+    damage: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    game.messages
+        .add("Choose target tile for the fireball", LIGHT_CYAN);
+    let target = target_tile(
+        tcod,
+        game,
+        objects,
+        Some(FIREBALL_RANGE as f32),
+        FIREBALL_RADIUS,
+    );
+    if let Some(center) = target {
+        game.messages.add(
+            format!(
+                "The fireball explodes, burning everything within {} tiles!",
+                FIREBALL_RADIUS
+            ),
+            ORANGE,
+        );
+        area_effect(
+            center,
+            FIREBALL_RADIUS,
+            game,
+            objects,
+            |id, game, objects| {
+                let damage = calculate_damage(damage as f32, magic_defense(&objects[id], game));
+                game.messages.add(
+                    format!(
+                        "{} gets burned by the fireball for {} hp",
+                        objects[id].name, damage
+                    ),
+                    ORANGE,
+                );
+                if let Some(xp) = objects[id].take_damage(damage, game) {
+                    if id != PLAYER {
+                        objects[PLAYER].get_xp(xp);
+                    }
+                }
+            },
+        );
+
+        UseResult::Used
+    } else {
+        game.messages.add("You need to choose a target", WHITE);
+        UseResult::Cancelled
+    }
+}
+
+fn effect_confuse(
+    num_turns: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let target = target_monster(
+        tcod,
+        game,
+        objects,
+        Some(CONFUSED_RANGE as f32),
+        "Choose target for confusion",
+    );
+    if let Some(target) = target {
+        let old_ai = objects[target].ai.take().unwrap_or(Ai::Basic);
+        objects[target].ai = Some(Ai::Confused {
+            previous_ai: Box::new(old_ai),
+            num_turns,
+        });
+        game.messages.add(
+            format!(
+                "The eyes of {} look vacant, as it starts to stumble around!",
+                objects[target].name
+            ),
+            YELLOW,
+        );
+
+        UseResult::Used
+    } else {
+        game.messages.add("You need to choose a target", WHITE);
+        UseResult::Cancelled
+    }
+}
+
+fn effect_heal(amount: i32, game: &mut Game, objects: &mut [Object]) -> UseResult {
+    if objects[PLAYER].fighter.map_or(0, |f| f.hp) == objects[PLAYER].max_hp(game) {
+        game.messages.add("You are already at full heal", YELLOW);
+        return UseResult::Cancelled;
+    }
+    game.messages
+        .add("your wounds start to feel better", YELLOW);
+    objects[PLAYER].heal(amount, game);
+
+    UseResult::Used
+}
+
+fn cast_lightning(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let damage = match game.inventory[inventory_id].item {
+        Some(Item::Lightning { damage }) => damage,
+        _ => return UseResult::Cancelled,
+    };
+    match effect_bolt("lightning", damage, LIGHTNING_RANGE, tcod, game, objects) {
+        UseResult::Used => UseResult::UsedUp,
+        result => result,
+    }
+}
+
+fn cast_fireball(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let (charges, damage) = match game.inventory[inventory_id].item {
+        Some(Item::Fireball { charges, damage }) => (charges, damage),
+        _ => {
+            println!("Something wierd happened, this should not happen.");
+            return UseResult::Cancelled;
+        }
+    };
+
+    match effect_fireball(damage, tcod, game, objects) {
+        UseResult::Used => {
+            game.inventory[inventory_id].item = Some(Item::Fireball {
+                charges: charges - 1,
+                damage,
+            });
+            if charges > 1 {
+                UseResult::Used
+            } else {
+                UseResult::UsedUp
+            }
+        }
+        result => result,
+    }
+}
+
+// the closest of the eight directions from the player towards a tile picked with the cursor
+fn target_direction(tcod: &mut Tcod, game: &mut Game, objects: &Vec<Object>) -> Option<(i32, i32)> {
+    let (x, y) = target_tile(tcod, game, objects, Some(WAND_RANGE as f32), 0)?;
+    let (dx, dy) = (x - objects[PLAYER].x, y - objects[PLAYER].y);
+    if (dx, dy) == (0, 0) {
+        return None;
+    }
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+    Some((
+        (dx as f32 / distance).round() as i32,
+        (dy as f32 / distance).round() as i32,
+    ))
+}
+
+// the tiles a zap passes, stopping short of the map's edge
+fn zap_path(from: (i32, i32), direction: (i32, i32), range: i32) -> Vec<(i32, i32)> {
+    (1..=range)
+        .map(|step| (from.0 + direction.0 * step, from.1 + direction.1 * step))
+        .take_while(|&(x, y)| x > 0 && y > 0 && x < MAP_WIDTH - 1 && y < MAP_HEIGHT - 1)
+        .collect()
+}
+
+// the first monster in the way of a bolt, which is stopped by walls
+fn bolt_target(path: &[(i32, i32)], game: &Game, objects: &[Object]) -> Option<usize> {
+    for &(x, y) in path {
+        if game.map[x as usize][y as usize].blocked {
+            return None;
+        }
+        let target = objects.iter().enumerate().position(|(id, o)| {
+            id != PLAYER && o.fighter.is_some() && o.ai.is_some() && o.pos() == (x, y)
+        });
+        if target.is_some() {
+            return target;
+        }
+    }
+    None
+}
+
+fn dig(path: &[(i32, i32)], tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let mut dug = false;
+    for &(x, y) in path {
+        let tile = &mut game.map[x as usize][y as usize];
+        if tile.blocked {
+            tile.blocked = false;
+            tile.block_sight = false;
+            tcod.fov.set(x, y, true, true);
+            dug = true;
+        }
+    }
+
+    if dug {
+        game.messages
+            .add("The rock crumbles away before you", LIGHT_SEPIA);
+        let player = &objects[PLAYER];
+        tcod.fov
+            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    } else {
+        game.messages
+            .add("The wand digs into nothing but air", LIGHT_GREY);
+    }
+}
+
+fn zap_wand(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let effect = match game.inventory[inventory_id].item {
+        Some(Item::Wand {
+            effect, charges, ..
+        }) if charges > 0 => effect,
+        Some(Item::Wand { .. }) => {
+            game.messages
+                .add("You wave the wand, but nothing happens", LIGHT_GREY);
+            return UseResult::Wasted;
+        }
+        _ => return UseResult::Cancelled,
+    };
+    let direction = match target_direction(tcod, game, objects) {
+        Some(direction) => direction,
+        None => return UseResult::Cancelled,
+    };
+    if let Some(Item::Wand {
+        ref mut charges, ..
+    }) = game.inventory[inventory_id].item
+    {
+        *charges -= 1;
+    }
+
+    let path = zap_path(objects[PLAYER].pos(), direction, WAND_RANGE);
+    if effect == WandEffect::Digging {
+        dig(&path, tcod, game, objects);
+        return UseResult::Used;
+    }
+
+    let target = match bolt_target(&path, game, objects) {
+        Some(target) => target,
+        None => {
+            game.messages.add("The bolt hits nothing", LIGHT_GREY);
+            return UseResult::Used;
+        }
+    };
+    match effect {
+        WandEffect::Digging => {}
+        WandEffect::Slow => {
+            let old_ai = objects[target].ai.take().unwrap_or(Ai::Basic);
+            objects[target].ai = Some(Ai::Slowed {
+                previous_ai: Box::new(old_ai),
+                num_turns: SLOWED_NUM_TURNS,
+            });
+            game.messages.add(
+                format!("The {} seems to move more slowly", objects[target].name),
+                LIGHT_BLUE,
+            );
+        }
+        WandEffect::Teleport => {
+            if let Some((x, y)) = random_free_tile(game, objects) {
+                game.messages.add(
+                    format!("The {} vanishes!", objects[target].name),
+                    LIGHT_VIOLET,
+                );
+                objects[target].set_pos(x, y);
+            }
+        }
+        WandEffect::Lightning => {
+            let (player, target) = mut_two(PLAYER, target, objects);
+            player.strike(
+                target,
+                game,
+                WAND_LIGHTNING_DAMAGE as f32,
+                false,
+                "with a bolt of lightning".into(),
+            );
+        }
+    }
+    UseResult::Used
+}
+
+// recharging adds charges, but the more often a wand was recharged the likelier it blows up
+fn cast_recharge(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let wands: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| matches!(game.inventory[id].item, Some(Item::Wand { .. })))
+        .collect();
+    if wands.is_empty() {
+        game.messages.add("You have nothing to recharge", YELLOW);
+        return UseResult::UsedUp;
+    }
+
+    let options: Vec<String> = wands
+        .iter()
+        .map(|&id| game.inventory[id].display_name(game))
+        .collect();
+    let wand = match menu(
+        "Choose a wand to recharge\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    ) {
+        Some(choice) => wands[choice],
+        None => return UseResult::Cancelled,
+    };
+    let (charges, recharged) = match game.inventory[wand].item {
+        Some(Item::Wand {
+            charges, recharged, ..
+        }) => (charges, recharged),
+        _ => return UseResult::Cancelled,
+    };
+
+    let blessing = game.inventory[inventory_id].blessing;
+    let mut explode_chance = RECHARGE_EXPLODE_CHANCE * recharged as f32;
+    if blessing == Blessing::Cursed {
+        explode_chance += RECHARGE_EXPLODE_CHANCE;
+    }
+    if rand::thread_rng().gen::<f32>() < explode_chance {
+        game.messages.add(
+            format!(
+                "Your {} vibrates violently and explodes!",
+                game.inventory[wand].display_name(game)
+            ),
+            ORANGE,
+        );
+        objects[PLAYER].take_damage(WAND_EXPLOSION_DAMAGE + 2 * charges, game);
+        // the scroll is used up here, so the inventory can't shift under it when the wand goes
+        let scroll_removed = game.inventory[inventory_id].count == 1;
+        take_from_inventory(inventory_id, 1, game);
+        let wand = if scroll_removed && wand > inventory_id {
+            wand - 1
+        } else {
+            wand
+        };
+        game.inventory.remove(wand);
+        return UseResult::Used;
+    }
+
+    let added =
+        blessing.potency(rand::thread_rng().gen_range(WAND_MIN_CHARGES, WAND_MAX_CHARGES + 1));
+    if let Some(Item::Wand {
+        ref mut charges,
+        ref mut recharged,
+        ..
+    }) = game.inventory[wand].item
+    {
+        *charges += added;
+        *recharged += 1;
+    }
+    game.messages.add(
+        format!(
+            "Your {} glows blue for a moment",
+            game.inventory[wand].display_name(game)
+        ),
+        LIGHT_VIOLET,
+    );
+    UseResult::UsedUp
+}
+
+fn zap_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let wands: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| matches!(game.inventory[id].item, Some(Item::Wand { .. })))
+        .collect();
+    if wands.is_empty() {
+        game.messages.add("You have no wands to zap", YELLOW);
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let options: Vec<String> = wands
+        .iter()
+        .map(|&id| game.inventory[id].display_name(game))
+        .collect();
+    match menu("Choose a wand to zap\n", &options, INVENTORY_WIDTH, tcod) {
+        Some(choice) => use_item(wands[choice], tcod, game, objects),
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+fn cast_confuse(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    match effect_confuse(CONFUSED_NUM_TURNS, tcod, game, objects) {
+        UseResult::Used => UseResult::UsedUp,
+        result => result,
+    }
+}
+
+fn cast_identify(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let unidentified: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| {
+            let item = &game.inventory[id];
+            id != inventory_id
+                && (!game.is_identified(&item.name)
+                    || item.equipment.is_some_and(|e| !e.identified)
+                    || matches!(
+                        item.item,
+                        Some(Item::Wand {
+                            charges_known: false,
+                            ..
+                        })
+                    ))
+        })
+        .collect();
+    if unidentified.is_empty() {
+        game.messages.add("You have nothing to identify", YELLOW);
+        return UseResult::UsedUp;
+    }
+
+    let options: Vec<String> = unidentified
+        .iter()
+        .map(|&id| game.inventory[id].display_name(game))
+        .collect();
+    let choice = menu(
+        "Choose an item to identify\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    );
+
+    match choice {
+        Some(choice) => {
+            let id = unidentified[choice];
+            let name = game.inventory[id].name.clone();
+            game.identify(&name);
+            if let Some(ref mut equipment) = game.inventory[id].equipment {
+                equipment.identified = true;
+                let bonuses = equipment.bonuses();
+                game.messages
+                    .add(format!("The {} has {}", name, bonuses), LIGHT_VIOLET);
+            }
+            if let Some(Item::Wand {
+                charges,
+                ref mut charges_known,
+                ..
+            }) = game.inventory[id].item
+            {
+                *charges_known = true;
+                game.messages.add(
+                    format!("The {} has {} charges left", name, charges),
+                    LIGHT_VIOLET,
+                );
+            }
+            UseResult::UsedUp
+        }
+        None => UseResult::Cancelled,
+    }
+}
+
+fn cast_magic_mapping(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    for column in game.map.iter_mut() {
+        for tile in column.iter_mut() {
+            tile.explored = true;
+        }
+    }
+    game.messages
+        .add("A map of the level forms in your mind", LIGHT_VIOLET);
+    UseResult::UsedUp
+}
+
+// every floor tile that can be walked to from `from`
+fn reachable_tiles(from: (i32, i32), map: &Map) -> Vec<(i32, i32)> {
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    seen[from.0 as usize][from.1 as usize] = true;
+    let mut tiles = vec![from];
+    let mut next = 0;
+    while next < tiles.len() {
+        let (x, y) = tiles[next];
+        next += 1;
+        for &(dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            let (x, y) = (x + dx, y + dy);
+            if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT {
+                continue;
+            }
+            if !seen[x as usize][y as usize] && !map[x as usize][y as usize].blocked {
+                seen[x as usize][y as usize] = true;
+                tiles.push((x, y));
+            }
+        }
+    }
+    tiles
+}
+
+fn cast_teleport(
+    _inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let free: Vec<(i32, i32)> = reachable_tiles(objects[PLAYER].pos(), &game.map)
+        .into_iter()
+        .filter(|&(x, y)| !is_blocked(x, y, &game.map, objects))
+        .collect();
+    match rand::thread_rng().choose(&free) {
+        Some(&(x, y)) => {
+            objects[PLAYER].set_pos(x, y);
+            game.messages
+                .add("You find yourself somewhere else", LIGHT_VIOLET);
+        }
+        None => game
+            .messages
+            .add("You feel a wrenching sensation", LIGHT_VIOLET),
+    }
+    UseResult::UsedUp
+}
+
+// monsters show up on the map wherever they are until the detection wears off
+fn cast_detect_monsters(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let blessing = game.inventory[inventory_id].blessing;
+    game.detect_monsters = blessing.potency(DETECT_MONSTERS_TURNS);
+    game.messages
+        .add("You sense the presence of monsters", LIGHT_VIOLET);
+    UseResult::UsedUp
+}
+
+// explores everything in sight, far beyond the reach of the torch
+fn cast_remove_fog(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    tcod.fov.compute_fov(
+        player_x,
+        player_y,
+        REMOVE_FOG_RADIUS,
+        FOV_LIGHT_WALLS,
+        FOV_ALGO,
+    );
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if tcod.fov.is_in_fov(x, y) {
+                game.map[x as usize][y as usize].explored = true;
+            }
+        }
+    }
+    tcod.fov
+        .compute_fov(player_x, player_y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    game.messages.add(
+        "The fog lifts and you can see far into the distance",
+        LIGHT_VIOLET,
+    );
+    UseResult::UsedUp
+}
+
+// an uncursed scroll frees the equipped items, a blessed one the whole inventory
+fn cast_remove_curse(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let blessing = game.inventory[inventory_id].blessing;
+    if blessing == Blessing::Cursed {
+        game.messages
+            .add("You feel as if you need some help", LIGHT_VIOLET);
+        return UseResult::UsedUp;
+    }
+
+    for (id, item) in game.inventory.iter_mut().enumerate() {
+        let equipped = item.equipment.is_some_and(|e| e.equipped);
+        if id != inventory_id
+            && item.blessing == Blessing::Cursed
+            && (equipped || blessing == Blessing::Blessed)
+        {
+            item.blessing = Blessing::Uncursed;
+        }
+    }
+    game.messages
+        .add("You feel like someone is helping you", LIGHT_VIOLET);
+
+    UseResult::UsedUp
+}
+
+// pouring holy water over an item lifts a curse or blesses it
+fn cast_holy_water(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let candidates: Vec<usize> = (0..game.inventory.len())
+        .filter(|&id| id != inventory_id && game.inventory[id].blessing != Blessing::Blessed)
+        .collect();
+    if candidates.is_empty() {
+        game.messages
+            .add("You have nothing to pour the holy water over", YELLOW);
+        return UseResult::Cancelled;
+    }
+
+    let options: Vec<String> = candidates
+        .iter()
+        .map(|&id| game.inventory[id].display_name(game))
+        .collect();
+    let choice = menu(
+        "Choose an item to pour the holy water over\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    );
+
+    match choice {
+        Some(choice) => {
+            let item = &mut game.inventory[candidates[choice]];
+            item.blessing = match item.blessing {
+                Blessing::Cursed => Blessing::Uncursed,
+                _ => Blessing::Blessed,
+            };
+            let message = match item.blessing {
+                Blessing::Uncursed => format!("The {} glows white for a moment", item.name),
+                _ => format!("The {} glows with a soft blue light", item.name),
+            };
+            game.messages.add(message, LIGHT_BLUE);
+            UseResult::UsedUp
+        }
+        None => UseResult::Cancelled,
+    }
+}
+
+// lets the player take items out of a container until they close it
+fn loot_menu(container_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    loop {
+        let contents = match objects[container_id].container {
+            Some(ref container) => &container.contents,
+            None => return,
+        };
+        if contents.is_empty() {
+            game.messages.add(
+                format!("The {} is empty", objects[container_id].name),
+                LIGHT_GREY,
+            );
+            return;
+        }
+
+        let mut options = vec!["Take everything".to_string()];
+        options.extend(contents.iter().map(|item| floor_item_name(item, game)));
+        let header = format!("The {} contains:\n", objects[container_id].name);
+        let choice = match menu(&header, &options, INVENTORY_WIDTH, tcod) {
+            Some(choice) => choice,
+            None => return,
+        };
+
+        let attributes = objects[PLAYER].attributes();
+        let container = objects[container_id].container.as_mut().unwrap();
+        let chosen: Vec<usize> = if choice == 0 {
+            (0..container.contents.len()).rev().collect()
+        } else {
+            vec![choice - 1]
+        };
+        for id in chosen {
+            if let Some(item) = add_to_inventory(container.contents.remove(id), &attributes, game) {
+                game.messages.add(
+                    format!(
+                        "Cannot take {} since inventory is full",
+                        item.display_name(game)
+                    ),
+                    YELLOW,
+                );
+                container.contents.insert(id, item);
+            }
+        }
+    }
+}
+
+// chests have to be forced open if locked, and might go off in the player's face
+fn open_chest(chest_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let (locked, trapped) = match objects[chest_id].container {
+        Some(ref container) => (container.locked, container.trapped),
+        None => return,
+    };
+
+    if locked {
+        if rand::thread_rng().gen::<f32>() >= FORCE_LOCK_CHANCE {
+            game.messages
+                .add("The chest is locked. You fail to force it open", LIGHT_GREY);
+            return;
+        }
+        game.messages.add("You force the lock open", LIGHT_GREY);
+    }
+    if trapped {
+        game.messages
+            .add("A poisoned needle springs out of the lock!", RED);
+        objects[PLAYER].take_damage(CHEST_TRAP_DAMAGE, game);
+    }
+    if let Some(ref mut container) = objects[chest_id].container {
+        container.locked = false;
+        container.trapped = false;
+    }
+
+    if objects[PLAYER].alive {
+        loot_menu(chest_id, tcod, game, objects);
+    }
+}
+
+fn open_bag(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let bag_name = game.inventory[inventory_id].name.clone();
+    let choice = menu(
+        &format!("What do you want to do with the {}?\n", bag_name),
+        &["Put something in", "Take something out"],
+        INVENTORY_WIDTH,
+        tcod,
+    );
+
+    match choice {
+        Some(0) => {
+            // bags don't go into bags, and worn equipment has to come off first
+            let candidates: Vec<usize> = (0..game.inventory.len())
+                .filter(|&id| {
+                    let item = &game.inventory[id];
+                    item.container.is_none() && !item.equipment.is_some_and(|e| e.equipped)
+                })
+                .collect();
+            let options: Vec<String> = candidates
+                .iter()
+                .map(|&id| floor_item_name(&game.inventory[id], game))
+                .collect();
+            let choice = menu(
+                &format!("Put what into the {}?\n", bag_name),
+                &options,
+                INVENTORY_WIDTH,
+                tcod,
+            );
+            let item_id = match choice {
+                Some(choice) => candidates[choice],
+                None => return UseResult::Cancelled,
+            };
+
+            let fits = game.inventory[inventory_id]
+                .container
+                .as_ref()
+                .is_some_and(|container| container.has_room_for(&game.inventory[item_id]));
+            if !fits {
+                game.messages
+                    .add(format!("The {} is full", bag_name), YELLOW);
+                return UseResult::Cancelled;
+            }
+
+            let item = game.inventory[item_id].clone();
+            game.messages.add(
+                format!("You put {} into the {}", item.display_name(game), bag_name),
+                YELLOW,
+            );
+            if let Some(ref mut container) = game.inventory[inventory_id].container {
+                container.store(item);
+            }
+            game.inventory.remove(item_id);
+            UseResult::Used
+        }
+        Some(_) => {
+            let contents = match game.inventory[inventory_id].container {
+                Some(ref container) => container.contents.clone(),
+                None => return UseResult::Cancelled,
+            };
+            if contents.is_empty() {
+                game.messages
+                    .add(format!("The {} is empty", bag_name), YELLOW);
+                return UseResult::Cancelled;
+            }
+
+            let options: Vec<String> = contents
+                .iter()
+                .map(|item| floor_item_name(item, game))
+                .collect();
+            let choice = menu(
+                &format!("Take what out of the {}?\n", bag_name),
+                &options,
+                INVENTORY_WIDTH,
+                tcod,
+            );
+            let choice = match choice {
+                Some(choice) => choice,
+                None => return UseResult::Cancelled,
+            };
+
+            // the bag itself takes up a slot, so only a free slot will do
+            let stacks = game
+                .inventory
+                .iter()
+                .any(|other| other.stacks_with(&contents[choice]));
+            if !stacks && game.inventory.len() >= INVENTORY_SLOTS {
+                game.messages
+                    .add("You have no room to take that out", YELLOW);
+                return UseResult::Cancelled;
+            }
+            let item = match game.inventory[inventory_id].container {
+                Some(ref mut container) => container.contents.remove(choice),
+                None => return UseResult::Cancelled,
+            };
+            if let Some(item) = add_to_inventory(item, &objects[PLAYER].attributes(), game) {
+                if let Some(ref mut container) = game.inventory[inventory_id].container {
+                    container.contents.insert(choice, item);
+                }
+                return UseResult::Cancelled;
+            }
+            UseResult::Used
+        }
+        None => UseResult::Cancelled,
+    }
+}
+
+// what a shopkeeper asks for a single item of a stack
+fn item_value(item: &Object) -> i32 {
+    let base = match item.item {
+        Some(Item::Heal { .. }) => 20,
+        Some(Item::ManaPotion) => 25,
+        Some(Item::HolyWater) => 60,
+        Some(Item::Lightning { .. }) => 40,
+        Some(Item::Fireball { .. }) => 60,
+        Some(Item::Confuse) => 30,
+        Some(Item::Identify) => 25,
+        Some(Item::Repair) => 30,
+        Some(Item::RemoveCurse) => 50,
+        Some(Item::MagicMapping) => 60,
+        Some(Item::Teleport) => 40,
+        Some(Item::DetectMonsters) => 30,
+        Some(Item::RemoveFog) => 20,
+        Some(Item::SpellBook { spell }) => 50 + 25 * spell.difficulty(),
+        Some(Item::Food { .. }) | Some(Item::Ingredient { .. }) => 5,
+        Some(Item::Recharge) => 80,
+        // the shopkeeper can't tell the charges of a wand any better than the player
+        Some(Item::Wand {
+            charges,
+            charges_known: true,
+            ..
+        }) => 60 + 10 * charges,
+        Some(Item::Wand { .. }) => 60 + 10 * (WAND_MIN_CHARGES + WAND_MAX_CHARGES) / 2,
+        Some(Item::Bag) => 40,
+        Some(Item::Arrows) | Some(Item::Bolts) | Some(Item::Corpse { .. }) | Some(Item::Gold) => 1,
+        _ => 10,
+    };
+
+    let value = match item.equipment {
+        Some(equipment) if equipment.slot != Slot::Ammunition => {
+            let bonuses = equipment.power_bonus
+                + equipment.defense_bonus
+                + equipment.accuracy_bonus
+                + equipment.evasion_bonus
+                + equipment.spellcast_bonus
+                + (equipment.max_hp_bonus + equipment.max_mana_bonus) / 5
+                + equipment.critical_bonus / 3
+                + (equipment.hp_regen_bonus + equipment.mana_regen_bonus) / 25;
+            let on_hit = if equipment.on_hit.is_some() { 40 } else { 0 };
+            let value = base + cmp::max(bonuses, 0) * 15 + on_hit + equipment.range * 2;
+            // worn out equipment is worth half as much
+            value * (equipment.durability + equipment.max_durability)
+                / (2 * equipment.max_durability)
+        }
+        _ => base,
+    };
+
+    cmp::max(item.blessing.potency(value), 1)
+}
+
+fn shop_owner(position: (i32, i32), objects: &[Object]) -> Option<usize> {
+    objects.iter().position(|object| match object.ai {
+        Some(Ai::Shopkeeper { x1, y1, x2, y2 }) => {
+            (x1..=x2).contains(&position.0) && (y1..=y2).contains(&position.1)
+        }
+        _ => false,
+    })
+}
+
+// a shopkeeper who is robbed or hurt stops trading and attacks
+// `reason` finishes the sentence "The shopkeeper gets angry ..."
+fn anger(shopkeeper: &mut Object, reason: &str, game: &mut Game) {
+    if let Some(Ai::Shopkeeper { .. }) = shopkeeper.ai {
+        shopkeeper.ai = Some(Ai::Basic);
+        game.messages.add(
+            format!("The {} gets angry {}!", shopkeeper.name, reason),
+            RED,
+        );
+    }
+}
+
+fn shop_menu(keeper_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let shop = match objects[keeper_id].ai {
+        Some(Ai::Shopkeeper { x1, y1, x2, y2 }) => (x1, y1, x2, y2),
+        _ => return,
+    };
+
+    loop {
+        let header = format!("\"Welcome, adventurer!\" You have {} gold.\n", game.gold);
+        match menu(&header, &["Buy", "Sell"], INVENTORY_WIDTH, tcod) {
+            Some(0) => buy_menu(shop, tcod, game, objects),
+            Some(_) => sell_menu(shop, tcod, game, objects),
+            None => return,
+        }
+    }
+}
+
+fn buy_menu(
+    shop: (i32, i32, i32, i32),
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    let (x1, y1, x2, y2) = shop;
+    loop {
+        let stock: Vec<usize> = (0..objects.len())
+            .filter(|&id| {
+                let object = &objects[id];
+                object.for_sale && (x1..=x2).contains(&object.x) && (y1..=y2).contains(&object.y)
+            })
+            .collect();
+        if stock.is_empty() {
+            game.messages
+                .add("There is nothing left for sale", LIGHT_GREY);
+            return;
+        }
+
+        let options: Vec<String> = stock
+            .iter()
+            .map(|&id| {
+                let item = &objects[id];
+                format!(
+                    "{} - {} gold",
+                    floor_item_name(item, game),
+                    item_value(item) * item.count
+                )
+            })
+            .collect();
+        let header = format!("Buy what? You have {} gold.\n", game.gold);
+        let id = match menu(&header, &options, INVENTORY_WIDTH, tcod) {
+            Some(choice) => stock[choice],
+            None => return,
+        };
+
+        let price = item_value(&objects[id]) * objects[id].count;
+        if price > game.gold {
+            game.messages.add(
+                format!("You can't afford the {}", objects[id].display_name(game)),
+                YELLOW,
+            );
+        } else if !has_room_for(&objects[id], game) {
+            game.messages.add("You have no room to carry that", YELLOW);
+        } else {
+            game.gold -= price;
+            let mut item = objects.swap_remove(id);
+            item.for_sale = false;
+            game.messages.add(
+                format!("You buy the {} for {} gold", item.display_name(game), price),
+                GOLD,
+            );
+            if let Some(item) = add_to_inventory(item, &objects[PLAYER].attributes(), game) {
+                objects.push(item);
+            }
+        }
+    }
+}
+
+// sold items are put on display in the shop, one at a time
+fn sell_menu(
+    shop: (i32, i32, i32, i32),
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    let (x1, y1, x2, y2) = shop;
+    loop {
+        let wares: Vec<usize> = (0..game.inventory.len())
+            .filter(|&id| {
+                let item = &game.inventory[id];
+                !item.equipment.is_some_and(|e| e.equipped)
+                    && item
+                        .container
+                        .as_ref()
+                        .is_none_or(|c| c.contents.is_empty())
+            })
+            .collect();
+        if wares.is_empty() {
+            game.messages
+                .add("You have nothing the shopkeeper wants", LIGHT_GREY);
+            return;
+        }
+
+        let options: Vec<String> = wares
+            .iter()
+            .map(|&id| {
+                let item = &game.inventory[id];
+                format!(
+                    "{} - {} gold",
+                    floor_item_name(item, game),
+                    cmp::max(item_value(item) / 2, 1)
+                )
+            })
+            .collect();
+        let header = format!("Sell what? You have {} gold.\n", game.gold);
+        let id = match menu(&header, &options, INVENTORY_WIDTH, tcod) {
+            Some(choice) => wares[choice],
+            None => return,
+        };
+
+        let price = cmp::max(item_value(&game.inventory[id]) / 2, 1);
+        let mut item = take_from_inventory(id, 1, game);
+        game.gold += price;
+        game.messages.add(
+            format!(
+                "You sell the {} for {} gold",
+                item.display_name(game),
+                price
+            ),
+            GOLD,
+        );
+        item.set_pos(
+            rand::thread_rng().gen_range(x1 + 1, x2),
+            rand::thread_rng().gen_range(y1 + 1, y2),
+        );
+        item.for_sale = true;
+        objects.push(item);
+    }
+}
+
+fn damaged_equipment(game: &Game) -> Vec<usize> {
+    (0..game.inventory.len())
+        .filter(|&id| {
+            game.inventory[id]
+                .equipment
+                .is_some_and(|e| e.slot != Slot::Ammunition && e.durability < e.max_durability)
+        })
+        .collect()
+}
+
+// asks which damaged item to restore, returning whether anything was repaired
+fn repair_menu(damaged: &[usize], tcod: &mut Tcod, game: &mut Game) -> bool {
+    let options: Vec<String> = damaged
+        .iter()
+        .map(|&id| {
+            let item = &game.inventory[id];
+            let equipment = item.equipment.unwrap();
+            format!(
+                "{} ({}, {}/{})",
+                item.display_name(game),
+                equipment.condition(),
+                equipment.durability,
+                equipment.max_durability
+            )
+        })
+        .collect();
+    let choice = menu(
+        "Choose an item to repair\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    );
+
+    match choice {
+        Some(choice) => {
+            let id = damaged[choice];
+            if let Some(ref mut equipment) = game.inventory[id].equipment {
+                equipment.durability = equipment.max_durability;
+            }
+            game.messages.add(
+                format!("Your {} is as good as new", game.inventory[id].name),
+                LIGHT_VIOLET,
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+fn cast_repair(
+    _inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let damaged = damaged_equipment(game);
+    if damaged.is_empty() {
+        game.messages.add("You have nothing to repair", YELLOW);
+        return UseResult::UsedUp;
+    }
+
+    if repair_menu(&damaged, tcod, game) {
+        UseResult::UsedUp
+    } else {
+        UseResult::Cancelled
+    }
+}
+
+// an anvil repairs a single item before cracking, returns whether a repair was made
+fn use_anvil(anvil_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> bool {
+    if objects[anvil_id].anvil.is_some_and(|anvil| anvil.cracked) {
+        game.messages
+            .add("The anvil is cracked and no longer of any use", LIGHT_GREY);
+        return false;
+    }
+
+    let damaged = damaged_equipment(game);
+    if damaged.is_empty() {
+        game.messages
+            .add("None of your equipment needs repairing", LIGHT_GREY);
+        false
+    } else if repair_menu(&damaged, tcod, game) {
+        game.messages
+            .add("The anvil cracks under the blows", LIGHT_GREY);
+        objects[anvil_id].anvil = Some(Anvil { cracked: true });
+        objects[anvil_id].name = "cracked anvil".into();
+        objects[anvil_id].color = DARK_GREY;
+        true
+    } else {
+        false
+    }
+}
+
+fn read_spellbook(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    _objects: &mut Vec<Object>,
+) -> UseResult {
+    let spell = match game.inventory[inventory_id].item {
+        Some(Item::SpellBook { spell }) => spell,
+        _ => return UseResult::Cancelled,
+    };
+    if game.spellbook.contains(&spell) {
+        game.messages
+            .add(format!("You already know {}", spell), YELLOW);
+        return UseResult::Cancelled;
+    }
+
+    game.messages.add(
+        format!("You study the book and learn to cast {}", spell),
+        LIGHT_VIOLET,
+    );
+    game.spellbook.push(spell);
+    UseResult::UsedUp
+}
+
+fn cast_mana_potion(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    if let Some(fighter) = objects[PLAYER].fighter {
+        if fighter.mana == objects[PLAYER].max_mana(game) {
+            game.messages.add("You are already at full mana", YELLOW);
+            return UseResult::Cancelled;
+        } else {
+            if let Some(Item::ManaPotion) = game.inventory[inventory_id].item {
+                let mut amount = game.inventory[inventory_id]
+                    .blessing
+                    .potency(MANA_REGENERATION);
+                if game.has_perk(Perk::PotentPotions) {
+                    amount = amount * 3 / 2;
+                }
+                game.messages.add("your arcane energy is bubbling", YELLOW);
+                objects[PLAYER].regenerate_mana(amount, game);
+
+                return UseResult::UsedUp;
+            }
+            println!(
+                "Tried to heal with an item that is not a mana regeneration {:?}",
+                game.inventory[inventory_id]
+            );
+        }
+    }
+    UseResult::Cancelled
+}
+
+fn cast_heal(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    if let Some(fighter) = objects[PLAYER].fighter {
+        if fighter.hp == objects[PLAYER].max_hp(game) {
+            game.messages.add("You are already at full heal", YELLOW);
+            return UseResult::Cancelled;
+        } else {
+            if let Some(Item::Heal { amount }) = game.inventory[inventory_id].item {
+                let mut amount = game.inventory[inventory_id].blessing.potency(amount);
+                if game.has_perk(Perk::PotentPotions) {
+                    amount = amount * 3 / 2;
+                }
+                game.messages
+                    .add("your wounds start to feel better", YELLOW);
+                objects[PLAYER].heal(amount, game);
+
+                return UseResult::UsedUp;
+            }
+            println!(
+                "Tried to heal with an item that is not a heal {:?}",
+                game.inventory[inventory_id]
+            );
+        }
+    }
+    UseResult::Cancelled
+}
+
+// sends `projectile` from the player towards `target`. It stops at the first wall or creature in
+// its way and lands there, except potions which shatter on impact.
+fn launch_projectile(
+    mut projectile: Object,
+    target: (i32, i32),
+    range: i32,
+    base_damage: i32,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    let from = objects[PLAYER].pos();
+    let mut landing = from;
+    let mut hit = None;
+
+    for (x, y) in tcod::line::Line::new(from, target) {
+        if game.map[x as usize][y as usize].blocked
+            || objects[PLAYER].distance_to_point((x, y)) > range as f32
+        {
+            break;
+        }
+        landing = (x, y);
+        hit = (0..objects.len()).find(|&id| {
+            id != PLAYER && objects[id].fighter.is_some() && objects[id].pos() == (x, y)
+        });
+        if hit.is_some() {
+            break;
+        }
+    }
+
+    match projectile.item {
+        Some(Item::Heal { amount }) => {
+            if let Some(id) = hit {
+                game.messages.add(
+                    format!(
+                        "The {} shatters over {}",
+                        projectile.display_name(game),
+                        objects[id].name
+                    ),
+                    LIGHT_VIOLET,
+                );
+                objects[id].heal(projectile.blessing.potency(amount), game);
+            } else {
+                game.messages.add(
+                    format!("The {} shatters", projectile.display_name(game)),
+                    LIGHT_VIOLET,
+                );
+            }
+            return;
+        }
+        Some(Item::ManaPotion) => {
+            if let Some(id) = hit {
+                game.messages.add(
+                    format!(
+                        "The {} shatters over {}",
+                        projectile.display_name(game),
+                        objects[id].name
+                    ),
+                    LIGHT_VIOLET,
+                );
+                objects[id].regenerate_mana(projectile.blessing.potency(MANA_REGENERATION), game);
+            } else {
+                game.messages.add(
+                    format!("The {} shatters", projectile.display_name(game)),
+                    LIGHT_VIOLET,
+                );
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    if let Some(id) = hit {
+        // fired ammunition carries the launcher's on-hit effects as well as its own
+        let mut effects: Vec<OnHit> = projectile
+            .equipment
+            .and_then(|e| e.on_hit)
+            .into_iter()
+            .collect();
+        if projectile.equipment.map(|e| e.slot) == Some(Slot::Ammunition) {
+            effects.extend(
+                objects[PLAYER]
+                    .get_all_equipped(game)
+                    .iter()
+                    .filter(|e| e.range > 0)
+                    .filter_map(|e| e.on_hit),
+            );
+        }
+
+        let (player, target) = mut_two(PLAYER, id, objects);
+        let hit = player.strike(
+            target,
+            game,
+            base_damage as f32,
+            true,
+            format!("with the {}", projectile.name),
+        );
+        if hit {
+            for effect in effects {
+                apply_on_hit(effect, player, target, game);
+            }
+        }
+    }
+
+    projectile.set_pos(landing.0, landing.1);
+    objects.push(projectile);
+}
+
+fn throw_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    if game.inventory[inventory_id].is_stuck() {
+        game.inventory[inventory_id].dequip(&mut game.messages);
+        return PlayerAction::DidntTakeTurn;
+    }
+    game.messages.add(
+        format!(
+            "Choose where to throw the {}",
+            game.inventory[inventory_id].display_name(game)
+        ),
+        LIGHT_CYAN,
+    );
+    let target = match target_tile(tcod, game, objects, Some(THROW_RANGE as f32), 0) {
+        Some(target) => target,
+        None => {
+            game.messages.add("Cancelled", WHITE);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    let mut item = take_from_inventory(inventory_id, 1, game);
+    if item.equipment.is_some() {
+        item.dequip(&mut game.messages);
+    }
+
+    let damage = match item.equipment {
+        Some(equipment) if equipment.slot != Slot::Ammunition && equipment.power_bonus > 0 => {
+            objects[PLAYER].unarmed_power(game) + equipment.worn().power_bonus
+        }
+        _ => THROWN_DAMAGE,
+    };
+
+    game.messages
+        .add(format!("You throw the {}", item.display_name(game)), YELLOW);
+    launch_projectile(item, target, THROW_RANGE, damage, game, objects);
+
+    PlayerAction::TookTurn
+}
+
+fn fire_weapon(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let launcher = game
+        .inventory
+        .iter()
+        .find_map(|o| match (o.item, o.equipment) {
+            (Some(item), Some(e)) if e.equipped && e.range > 0 => Some((item, e.range)),
+            _ => None,
+        });
+    let (launcher, range) = match launcher {
+        Some(launcher) => launcher,
+        None => {
+            game.messages
+                .add("You are not holding a ranged weapon.", YELLOW);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    let ammo_id = get_equipped_in_slot(Slot::Ammunition, &game.inventory)
+        .filter(|&id| game.inventory[id].item.is_some_and(|i| launcher.fires(i)));
+    let ammo_id = match ammo_id {
+        Some(ammo_id) => ammo_id,
+        None => {
+            game.messages
+                .add("You have no fitting ammunition in your quiver.", YELLOW);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    game.messages.add(
+        format!("Choose target for {}", game.inventory[ammo_id].name),
+        LIGHT_CYAN,
+    );
+    let target = match target_tile(tcod, game, objects, Some(range as f32), 0) {
+        Some(target) => target,
+        None => {
+            game.messages.add("Cancelled", WHITE);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    let damage = objects[PLAYER].ranged_power(game);
+
+    // take a single piece of ammunition out of the quiver
+    if game.inventory[ammo_id].count == 1 {
+        game.messages.add(
+            format!("You are out of {}", game.inventory[ammo_id].name),
+            YELLOW,
+        );
+    }
+    let mut projectile = take_from_inventory(ammo_id, 1, game);
+    if let Some(ref mut equipment) = projectile.equipment {
+        equipment.equipped = false;
+    }
+
+    wear_equipment(game, |e| e.range > 0);
+    launch_projectile(projectile, target, range, damage, game, objects);
+
+    PlayerAction::TookTurn
+}
+
+// the inventory has a fixed number of slots, once they are full items go into a bag with room
+fn has_room_for(item: &Object, game: &Game) -> bool {
+    item.item == Some(Item::Gold)
+        || game.inventory.iter().any(|other| other.stacks_with(item))
+        || game.inventory.len() < INVENTORY_SLOTS
+        || game.inventory.iter().any(|bag| {
+            bag.container
+                .as_ref()
+                .is_some_and(|container| container.has_room_for(item))
+        })
+}
+
+// hands the item back if there is no room for it anywhere, `attributes` are the player's
+fn add_to_inventory(item: Object, attributes: &Attributes, game: &mut Game) -> Option<Object> {
+    if item.item == Some(Item::Gold) {
+        game.messages
+            .add(format!("You pick up {} gold", item.count), GOLD);
+        game.gold += item.count;
+        return None;
+    }
+
+    let stack_id = game
+        .inventory
+        .iter()
+        .position(|other| other.stacks_with(&item));
+    if let Some(stack_id) = stack_id {
+        game.messages
+            .add(format!("You picked up {}", item.display_name(game)), YELLOW);
+        game.inventory[stack_id].count += item.count;
+        return None;
+    }
+
+    if game.inventory.len() < INVENTORY_SLOTS {
+        game.messages
+            .add(format!("You picked up {}", item.display_name(game)), YELLOW);
+        let index = game.inventory.len();
+        let (equipment, blessing) = (item.equipment, item.blessing);
+        game.inventory.push(item);
+
+        // gear that is unknown, and so might be cursed, is only put on by hand
+        let safe = equipment.filter(|e| e.identified && blessing != Blessing::Cursed);
+        if let Some(equipment) = safe {
+            let (slot, conflicts) = slot_conflicts(&equipment, &game.inventory);
+            if conflicts.is_empty() && equipment.usable_with(attributes) {
+                if let Some(ref mut equipment) = game.inventory[index].equipment {
+                    equipment.slot = slot;
+                }
+                game.inventory[index].equip(&mut game.messages);
+            }
+        }
+        return None;
+    }
+
+    let bag_id = game.inventory.iter().position(|bag| {
+        bag.container
+            .as_ref()
+            .is_some_and(|container| container.has_room_for(&item))
+    });
+    if let Some(bag_id) = bag_id {
+        game.messages.add(
+            format!(
+                "You put {} into your {}",
+                item.display_name(game),
+                game.inventory[bag_id].name
+            ),
+            YELLOW,
+        );
+        if let Some(ref mut container) = game.inventory[bag_id].container {
+            container.store(item);
+        }
+        return None;
+    }
+    Some(item)
+}
+
+fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    if has_room_for(&objects[object_id], game) {
+        let keeper_id = shop_owner(objects[object_id].pos(), objects);
+        if let Some(keeper_id) = keeper_id.filter(|_| objects[object_id].for_sale) {
+            game.messages.add(
+                format!("You steal the {}!", objects[object_id].display_name(game)),
+                ORANGE,
+            );
+            anger(&mut objects[keeper_id], "at the theft", game);
+        }
+        let mut item = objects.swap_remove(object_id);
+        item.for_sale = false;
+        if let Some(item) = add_to_inventory(item, &objects[PLAYER].attributes(), game) {
+            objects.push(item);
+        }
+    } else {
+        game.messages.add(
+            format!(
+                "Cannot pick up {} since inventory is full",
+                objects[object_id].display_name(game)
+            ),
+            YELLOW,
+        );
+    }
+}
+
+fn items_at(x: i32, y: i32, objects: &[Object]) -> Vec<usize> {
+    objects
+        .iter()
+        .enumerate()
+        .filter(|(_, object)| object.pos() == (x, y) && object.item.is_some())
+        .map(|(id, _)| id)
+        .collect()
+}
+
+fn floor_item_name(item: &Object, game: &Game) -> String {
+    if item.count > 1 {
+        format!("{} ({})", item.display_name(game), item.count)
+    } else {
+        item.display_name(game)
+    }
+}
+
+// picks up the only item under the player, or asks which one when there are several
+fn pick_up_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let (x, y) = objects[PLAYER].pos();
+    let item_ids = items_at(x, y, objects);
+
+    match item_ids.len() {
+        0 => game
+            .messages
+            .add("There is nothing here to pick up.", LIGHT_GREY),
+        1 => pick_item_up(item_ids[0], game, objects),
+        _ => {
+            let mut options = vec!["All items".to_string()];
+            options.extend(
+                item_ids
+                    .iter()
+                    .map(|&id| floor_item_name(&objects[id], game)),
+            );
+            let choice = menu("Pick up which item?\n", &options, INVENTORY_WIDTH, tcod);
+            match choice {
+                // highest ids first, so removing one doesn't move the others
+                Some(0) => {
+                    for &id in item_ids.iter().rev() {
+                        pick_item_up(id, game, objects);
+                    }
+                }
+                Some(choice) => pick_item_up(item_ids[choice - 1], game, objects),
+                None => {}
+            }
+        }
+    }
+}
+
+// lists the items on the floor where the player is standing
+fn describe_floor(game: &mut Game, objects: &[Object]) {
+    let (x, y) = objects[PLAYER].pos();
+    let names: Vec<String> = items_at(x, y, objects)
+        .iter()
+        .map(|&id| floor_item_name(&objects[id], game))
+        .collect();
+
+    match names.len() {
+        0 => {}
+        1 => game
+            .messages
+            .add(format!("You see {} here.", names[0]), LIGHT_GREY),
+        _ => game
+            .messages
+            .add(format!("Things here: {}.", names.join(", ")), LIGHT_GREY),
+    }
+}
+
+// removes `amount` items from the stack at `inventory_id`, or the whole stack if that is all of it
+fn take_from_inventory(inventory_id: usize, amount: i32, game: &mut Game) -> Object {
+    if amount >= game.inventory[inventory_id].count {
+        game.inventory.remove(inventory_id)
+    } else {
+        let mut item = game.inventory[inventory_id].clone();
+        item.count = amount;
+        if let Some(ref mut equipment) = item.equipment {
+            equipment.equipped = false;
+        }
+        game.inventory[inventory_id].count -= amount;
+        item
+    }
+}
+
+fn choose_quantity(count: i32, verb: &str, tcod: &mut Tcod) -> Option<i32> {
+    if count <= 1 {
+        return Some(count);
+    }
+
+    let mut amounts = vec![1];
+    if count / 2 > 1 {
+        amounts.push(count / 2);
+    }
+    amounts.push(count);
+    let options: Vec<String> = amounts
+        .iter()
+        .map(|amount| format!("{} {}", verb, amount))
+        .collect();
+
+    menu("How many?\n", &options, INVENTORY_WIDTH, tcod).map(|choice| amounts[choice])
+}
+
+fn drop_item(inventory_id: usize, amount: i32, game: &mut Game, objects: &mut Vec<Object>) {
+    if game.inventory[inventory_id].is_stuck() {
+        game.inventory[inventory_id].dequip(&mut game.messages);
+        return;
+    }
+    let mut item = take_from_inventory(inventory_id, amount, game);
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+    game.messages
+        .add(format!("You dropped {}", item.display_name(game)), YELLOW);
+    if item.equipment.is_some() {
+        item.dequip(&mut game.messages);
+    }
+    objects.push(item);
+}
+
+fn toggle_equipment(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let equipment = match game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    if equipment.equipped {
+        if !game.inventory[inventory_id].dequip(&mut game.messages) {
+            return UseResult::Cancelled;
+        }
+    } else {
+        if let Some((attribute, value)) = equipment.requirement {
+            if objects[PLAYER].attributes().get(attribute) < value {
+                game.messages.add(
+                    format!(
+                        "You need {} {} to use the {}",
+                        value, attribute, game.inventory[inventory_id].name
+                    ),
+                    ORANGE,
+                );
+                return UseResult::Cancelled;
+            }
+        }
+        let (slot, conflicts) = slot_conflicts(&equipment, &game.inventory);
+        let stuck = conflicts
+            .iter()
+            .cloned()
+            .find(|&id| game.inventory[id].is_stuck());
+        if let Some(stuck) = stuck {
+            game.messages.add(
+                format!(
+                    "You can't equip the {} while the cursed {} is stuck to your {}",
+                    game.inventory[inventory_id].name,
+                    game.inventory[stuck].name,
+                    game.inventory[stuck].equipment.unwrap().slot
+                ),
+                ORANGE,
+            );
+            return UseResult::Cancelled;
+        }
+        for current in conflicts {
+            game.inventory[current].dequip(&mut game.messages);
+        }
+        if let Some(ref mut equipment) = game.inventory[inventory_id].equipment {
+            equipment.slot = slot;
+        }
+        game.inventory[inventory_id].equip(&mut game.messages);
+    }
+
+    UseResult::Used
+}
+
+fn spell_effect(
+    spell: Spell,
+    power: i32,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    match spell {
+        Spell::MagicMissile => effect_bolt(
+            "magic missile",
+            power,
+            MAGIC_MISSILE_RANGE,
+            tcod,
+            game,
+            objects,
+        ),
+        Spell::Heal => effect_heal(power, game, objects),
+        Spell::Confuse => effect_confuse(power, tcod, game, objects),
+        Spell::Lightning => effect_bolt("lightning", power, LIGHTNING_RANGE, tcod, game, objects),
+        Spell::Fireball => effect_fireball(power, tcod, game, objects),
+    }
+}
+
+// what a spell costs the player, which mana surge lowers
+fn spell_cost(spell: Spell, game: &Game) -> i32 {
+    if game.has_perk(Perk::ManaSurge) {
+        spell.mana_cost() * 3 / 4
+    } else {
+        spell.mana_cost()
+    }
+}
+
+fn cast_spell(
+    spell: Spell,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    let mana = objects[PLAYER].fighter.map_or(0, |f| f.mana);
+    let cost = spell_cost(spell, game);
+    if mana < cost {
+        game.messages.add(
+            format!("You do not have enough mana to cast {}", spell),
+            YELLOW,
+        );
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let spellcast = objects[PLAYER].spellcast(game);
+    let spend_mana = |objects: &mut Vec<Object>| {
+        if let Some(ref mut fighter) = objects[PLAYER].fighter {
+            fighter.mana -= cost;
+        }
+    };
+
+    if rand::thread_rng().gen::<f32>() < spell.failure_chance(spellcast) {
+        game.messages
+            .add(format!("Your {} spell fizzles", spell), LIGHT_GREY);
+        spend_mana(objects);
+        return PlayerAction::TookTurn;
+    }
+
+    match spell_effect(spell, spell.power(spellcast), tcod, game, objects) {
+        UseResult::Cancelled => {
+            game.messages.add("Cancelled", WHITE);
+            PlayerAction::DidntTakeTurn
+        }
+        _ => {
+            spend_mana(objects);
+            PlayerAction::TookTurn
+        }
+    }
+}
+
+fn spell_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    if game.spellbook.is_empty() {
+        game.messages.add("You do not know any spells", YELLOW);
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let spellcast = objects[PLAYER].spellcast(game);
+    let options: Vec<String> = game
+        .spellbook
+        .iter()
+        .map(|spell| {
+            format!(
+                "{} ({} mana, {:.0}% to fail)",
+                spell,
+                spell_cost(*spell, game),
+                spell.failure_chance(spellcast) * 100.0
+            )
+        })
+        .collect();
+
+    let choice = menu(
+        "Press the key next to a spell to cast it, or any other to cancel.\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    );
+    if let Some(choice) = choice {
+        let spell = game.spellbook[choice];
+        cast_spell(spell, tcod, game, objects)
+    } else {
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+fn eat(
+    inventory_id: usize,
+    _tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> UseResult {
+    let (nutrition, effect) = match game.inventory[inventory_id].item {
+        Some(Item::Food { nutrition }) => (nutrition, CorpseEffect::Nothing),
+        Some(Item::Corpse { nutrition, effect }) => (nutrition, effect),
+        _ => return UseResult::Cancelled,
+    };
+    if Hunger::from_nutrition(game.nutrition) == Hunger::Satiated {
+        game.messages.add("You are too full to eat", YELLOW);
+        return UseResult::Cancelled;
+    }
+
+    game.nutrition = cmp::min(game.nutrition + nutrition, MAX_NUTRITION);
+    game.messages.add(
+        format!("You eat the {}", game.inventory[inventory_id].name),
+        YELLOW,
+    );
+
+    match effect {
+        CorpseEffect::Nothing => {}
+        CorpseEffect::Regeneration => {
+            game.messages
+                .add("The flesh knits your wounds together", LIGHT_GREEN);
+            objects[PLAYER].heal(TROLL_CORPSE_HEAL, game);
+        }
+        CorpseEffect::Sickness if game.has_perk(Perk::IronStomach) => {
+            game.messages
+                .add("It tastes foul, but your stomach can take it", YELLOW);
+        }
+        CorpseEffect::Sickness => {
+            game.messages.add("You feel sick", LIGHT_RED);
+            objects[PLAYER].take_damage(SICKNESS_DAMAGE, game);
+        }
+    }
+
+    UseResult::UsedUp
+}
+
+enum UseResult {
+    UsedUp,
+    Used,
+    // the turn is spent, but nothing is learnt about the item
+    Wasted,
+    Cancelled,
+}
+
+fn use_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    use Item::*;
+
+    if let Some(item) = game.inventory[inventory_id].item {
+        let name = game.inventory[inventory_id].name.clone();
+        let on_use = match item {
+            Heal { .. } => cast_heal,
+            ManaPotion => cast_mana_potion,
+            Lightning { .. } => cast_lightning,
+            Fireball { .. } => cast_fireball,
+            Staff => toggle_equipment,
+            SpellBook { .. } => read_spellbook,
+            Confuse => cast_confuse,
+            Sword => toggle_equipment,
+            Shield => toggle_equipment,
+            Helmet => toggle_equipment,
+            BodyArmor => toggle_equipment,
+            Greatsword => toggle_equipment,
+            Ring => toggle_equipment,
+            Amulet => toggle_equipment,
+            Boots => toggle_equipment,
+            Cloak => toggle_equipment,
+            Gloves => toggle_equipment,
+            Bow => toggle_equipment,
+            Crossbow => toggle_equipment,
+            Arrows => toggle_equipment,
+            Bolts => toggle_equipment,
+            Identify => cast_identify,
+            Repair => cast_repair,
+            Recharge => cast_recharge,
+            MagicMapping => cast_magic_mapping,
+            Teleport => cast_teleport,
+            DetectMonsters => cast_detect_monsters,
+            RemoveFog => cast_remove_fog,
+            Wand { .. } => zap_wand,
+            RemoveCurse => cast_remove_curse,
+            HolyWater => cast_holy_water,
+            Bag => open_bag,
+            // gold goes straight into the purse and never shows up in the inventory
+            Gold => return PlayerAction::DidntTakeTurn,
+            Relic => {
+                game.messages
+                    .add(format!("The {} is wanted by someone", name), LIGHT_CYAN);
+                return PlayerAction::DidntTakeTurn;
+            }
+            Food { .. } => eat,
+            Corpse { .. } => eat,
+            Ingredient { .. } => {
+                game.messages
+                    .add(format!("The {} is only good for crafting", name), WHITE);
+                return PlayerAction::DidntTakeTurn;
+            }
+        };
+        match on_use(inventory_id, tcod, game, objects) {
+            UseResult::UsedUp => {
+                game.identify(&name);
+                game.messages.add(format!("{} is used up", name), YELLOW);
+                take_from_inventory(inventory_id, 1, game);
+                PlayerAction::TookTurn
+            }
+            UseResult::Cancelled => {
+                game.messages.add("Cancelled", WHITE);
+                PlayerAction::DidntTakeTurn
+            }
+            UseResult::Used => {
+                game.identify(&name);
+                PlayerAction::TookTurn
+            }
+            UseResult::Wasted => PlayerAction::TookTurn,
+        }
+    } else {
+        game.messages.add(
+            format!(
+                "the {} cannot be used",
+                game.inventory[inventory_id].display_name(game)
+            ),
+            WHITE,
+        );
+        PlayerAction::DidntTakeTurn
+    }
+}
+
+// a fighter's primary attributes, from which most of the combat stats are derived
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Attributes {
+    strength: i32,
+    dexterity: i32,
+    constitution: i32,
+    intelligence: i32,
+    willpower: i32,
+}
+
+impl Attributes {
+    // an ordinary creature, with neither bonuses nor penalties
+    pub fn average() -> Self {
+        Attributes {
+            strength: ATTRIBUTE_AVERAGE,
+            dexterity: ATTRIBUTE_AVERAGE,
+            constitution: ATTRIBUTE_AVERAGE,
+            intelligence: ATTRIBUTE_AVERAGE,
+            willpower: ATTRIBUTE_AVERAGE,
+        }
+    }
+
+    pub fn get(&self, attribute: Attribute) -> i32 {
+        match attribute {
+            Attribute::Strength => self.strength,
+            Attribute::Dexterity => self.dexterity,
+            Attribute::Constitution => self.constitution,
+            Attribute::Intelligence => self.intelligence,
+            Attribute::Willpower => self.willpower,
+        }
+    }
+
+    // how far the attribute is above average
+    pub fn excess(&self, attribute: Attribute) -> i32 {
+        self.get(attribute) - ATTRIBUTE_AVERAGE
+    }
+
+    // the bonus to the derived stats, one point for every two above average
+    pub fn modifier(&self, attribute: Attribute) -> i32 {
+        self.excess(attribute).div_euclid(2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Attribute {
+    Strength,
+    Dexterity,
+    Constitution,
+    Intelligence,
+    Willpower,
+}
+
+impl Attribute {
+    const ALL: [Attribute; 5] = [
+        Attribute::Strength,
+        Attribute::Dexterity,
+        Attribute::Constitution,
+        Attribute::Intelligence,
+        Attribute::Willpower,
+    ];
+}
+
+impl std::fmt::Display for Attribute {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Attribute::Strength => write!(f, "strength"),
+            Attribute::Dexterity => write!(f, "dexterity"),
+            Attribute::Constitution => write!(f, "constitution"),
+            Attribute::Intelligence => write!(f, "intelligence"),
+            Attribute::Willpower => write!(f, "willpower"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Fighter {
+    base_max_hp: i32,
+    hp: i32,
+    base_defense: i32,
+    base_power: i32,
+    mana: i32,
+    base_max_mana: i32,
+    base_spellcast_modifier: i32,
+    #[serde(default)]
+    base_accuracy: i32,
+    #[serde(default)]
+    base_evasion: i32,
+    #[serde(default)]
+    hp_regen_progress: f32,
+    #[serde(default)]
+    mana_regen_progress: f32,
+    #[serde(default = "Attributes::average")]
+    attributes: Attributes,
+    xp: i32,
+    on_death: DeathCallback,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum DeathCallback {
+    Player,
+    Monster,
+    MonsterArcher,
+}
+
+impl DeathCallback {
+    fn callback(self, object: &mut Object, game: &mut Game) {
+        use DeathCallback::*;
+        let callback: fn(&mut Object, &mut Game) = match self {
+            Player => player_death,
+            Monster => monster_death,
+            MonsterArcher => archer_death,
+        };
+        callback(object, game);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Ai {
+    Basic,
+    Confused {
+        previous_ai: Box<Ai>,
+        num_turns: i32,
+    },
+    // only gets to act every other turn
+    Slowed {
+        previous_ai: Box<Ai>,
+        num_turns: i32,
+    },
+    Archer {
+        range: i32,
+    },
+    // stands around until somebody talks to it
+    Npc {
+        dialogue: String,
+    },
+    // keeps close to the player, even down the stairs
+    Follower {
+        dialogue: String,
+    },
+    // stays behind the counter of the shop spanning these tiles
+    Shopkeeper {
+        x1: i32,
+        y1: i32,
+        x2: i32,
+        y2: i32,
+    },
+}
+
+impl Ai {
+    // peaceful NPCs, companions and shopkeepers that haven't been angered don't count as enemies
+    pub fn is_hostile(&self) -> bool {
+        match *self {
+            Ai::Npc { .. } | Ai::Follower { .. } | Ai::Shopkeeper { .. } => false,
+            Ai::Confused {
+                ref previous_ai, ..
             }
-
-            rooms.push(new_room);
+            | Ai::Slowed {
+                ref previous_ai, ..
+            } => previous_ai.is_hostile(),
+            Ai::Basic | Ai::Archer { .. } => true,
         }
     }
+}
 
-    let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
-    let mut stairs = Object::new(last_room_x, last_room_y, '<', "stairs", WHITE, false);
-    stairs.always_visible = true;
-    objects.push(stairs);
-
-    map
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+    Exit,
 }
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
-    }
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Item {
+    Heal {
+        amount: i32,
+    },
+    Lightning {
+        damage: i32,
+    },
+    Fireball {
+        charges: i32,
+        damage: i32,
+    },
+    Confuse,
+    Sword,
+    Shield,
+    Helmet,
+    BodyArmor,
+    ManaPotion,
+    Staff,
+    SpellBook {
+        spell: Spell,
+    },
+    Bow,
+    Crossbow,
+    Arrows,
+    Bolts,
+    Identify,
+    Repair,
+    Recharge,
+    MagicMapping,
+    Teleport,
+    DetectMonsters,
+    RemoveFog,
+    RemoveCurse,
+    HolyWater,
+    Bag,
+    Gold,
+    Relic,
+    Food {
+        nutrition: i32,
+    },
+    Greatsword,
+    Ring,
+    Amulet,
+    Boots,
+    Cloak,
+    Gloves,
+    Corpse {
+        nutrition: i32,
+        effect: CorpseEffect,
+    },
+    Ingredient {
+        kind: IngredientKind,
+    },
+    Wand {
+        effect: WandEffect,
+        charges: i32,
+        // how often it was recharged, which makes the next recharge riskier
+        recharged: i32,
+        // the number of charges is only shown once identified
+        charges_known: bool,
+    },
 }
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
-    }
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Blessing {
+    Cursed,
+    #[default]
+    Uncursed,
+    Blessed,
 }
 
-fn render_all(tcod: &mut Tcod, game: &mut Game, objects: &Vec<Object>, fov_recompute: bool) {
-    if fov_recompute {
-        let player = &objects[PLAYER];
-        tcod.fov
-            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+impl Blessing {
+    fn random() -> Blessing {
+        let roll = rand::thread_rng().gen::<f32>();
+        if roll < CURSED_CHANCE {
+            Blessing::Cursed
+        } else if roll < CURSED_CHANCE + BLESSED_CHANCE {
+            Blessing::Blessed
+        } else {
+            Blessing::Uncursed
+        }
     }
 
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-            let wall = game.map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
-            };
-
-            let explored = &mut game.map[x as usize][y as usize].explored;
-            if visible {
-                *explored = true;
-            }
-            if *explored {
-                tcod.con
-                    .set_char_background(x, y, color, BackgroundFlag::Set);
-            }
+    // how strongly a potion works
+    fn potency(self, amount: i32) -> i32 {
+        match self {
+            Blessing::Cursed => amount / 2,
+            Blessing::Uncursed => amount,
+            Blessing::Blessed => amount * 3 / 2,
         }
     }
+}
 
-    let mut to_draw: Vec<_> = objects
-        .iter()
-        .filter(|o| {
-            tcod.fov.is_in_fov(o.x, o.y)
-                || (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
-        })
-        .collect();
-
-    to_draw.sort_by(|o1, o2| o1.blocks.cmp(&o2.blocks));
-
-    for object in &to_draw {
-        object.draw(&mut tcod.con);
+impl std::fmt::Display for Blessing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Blessing::Cursed => write!(f, "cursed"),
+            Blessing::Uncursed => write!(f, "uncursed"),
+            Blessing::Blessed => write!(f, "blessed"),
+        }
     }
+}
 
-    blit(
-        &tcod.con,
-        (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
-        &mut tcod.root,
-        (0, 0),
-        1.0,
-        1.0,
-    );
-
-    tcod.panel.set_default_background(BLACK);
-    tcod.panel.clear();
-
-    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
-    let max_hp = objects[PLAYER].max_hp(game);
-
-    render_bar(
-        &mut tcod.panel,
-        1,
-        1,
-        BAR_WIDTH,
-        "HP",
-        hp,
-        max_hp,
-        LIGHT_RED,
-        DARKER_RED,
-    );
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum CorpseEffect {
+    Nothing,
+    Regeneration,
+    Sickness,
+}
 
-    let mana = objects[PLAYER].fighter.map_or(0, |f| f.mana);
-    let max_mana = objects[PLAYER].max_mana(game);
+fn corpse_of(monster: &str) -> Item {
+    match monster {
+        "troll" => Item::Corpse {
+            nutrition: 500,
+            effect: CorpseEffect::Regeneration,
+        },
+        "archer" => Item::Corpse {
+            nutrition: 200,
+            effect: CorpseEffect::Sickness,
+        },
+        _ => Item::Corpse {
+            nutrition: 300,
+            effect: CorpseEffect::Nothing,
+        },
+    }
+}
 
-    render_bar(
-        &mut tcod.panel,
-        1,
-        2,
-        BAR_WIDTH,
-        "MANA",
-        mana,
-        max_mana,
-        LIGHT_BLUE,
-        DARKER_BLUE,
-    );
+// what is left of a monster for crafting, besides its corpse
+fn ingredient_of(monster: &str) -> Option<Item> {
+    let kind = match monster {
+        "troll" => IngredientKind::TrollHide,
+        "orc" => IngredientKind::OrcTusk,
+        "archer" => IngredientKind::Feathers,
+        _ => return None,
+    };
+    Some(Item::Ingredient { kind })
+}
 
-    tcod.panel.print_ex(
-        1,
-        3,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        format!("Dungeon level: {}", game.dungeon_level),
-    );
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum IngredientKind {
+    TrollHide,
+    OrcTusk,
+    Feathers,
+}
 
-    let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in game.messages.iter().rev() {
-        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
-        y -= msg_height;
-        if y <= 0 {
-            break;
+impl std::fmt::Display for IngredientKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            IngredientKind::TrollHide => write!(f, "troll hide"),
+            IngredientKind::OrcTusk => write!(f, "orc tusk"),
+            IngredientKind::Feathers => write!(f, "bundle of feathers"),
         }
-
-        tcod.panel.set_default_foreground(color);
-        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
     }
-
-    tcod.panel.set_default_foreground(LIGHT_GREY);
-    tcod.panel.print_ex(
-        1,
-        0,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        get_names_under_mouse(tcod.mouse, objects, &tcod.fov),
-    );
-
-    blit(
-        &tcod.panel,
-        (0, 0),
-        (SCREEN_WIDTH, PANEL_HEIGHT),
-        &mut tcod.root,
-        (0, PANEL_Y),
-        1.0,
-        1.0,
-    );
 }
 
-fn render_bar(
-    panel: &mut Offscreen,
-    x: i32,
-    y: i32,
-    total_width: i32,
-    name: &str,
-    value: i32,
-    maximum: i32,
-    bar_color: Color,
-    back_color: Color,
-) {
-    let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Hunger {
+    Satiated,
+    NotHungry,
+    Hungry,
+    Weak,
+    Starving,
+}
 
-    panel.set_default_background(back_color);
-    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+impl Hunger {
+    pub fn from_nutrition(nutrition: i32) -> Self {
+        if nutrition > SATIATED_NUTRITION {
+            Hunger::Satiated
+        } else if nutrition > HUNGRY_NUTRITION {
+            Hunger::NotHungry
+        } else if nutrition > WEAK_NUTRITION {
+            Hunger::Hungry
+        } else if nutrition > 0 {
+            Hunger::Weak
+        } else {
+            Hunger::Starving
+        }
+    }
 
-    panel.set_default_background(bar_color);
-    if bar_width > 0 {
-        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+    // subtracted from attack and accuracy
+    pub fn penalty(self) -> i32 {
+        match self {
+            Hunger::Weak => 1,
+            Hunger::Starving => 2,
+            _ => 0,
+        }
     }
 
-    panel.set_default_foreground(WHITE);
-    panel.print_ex(
-        x + total_width / 2,
-        y,
-        BackgroundFlag::None,
-        TextAlignment::Center,
-        &format!("{}: {}/{}", name, value, maximum),
-    );
+    pub fn color(self) -> Color {
+        match self {
+            Hunger::Satiated => LIGHT_GREEN,
+            Hunger::NotHungry => WHITE,
+            Hunger::Hungry => YELLOW,
+            Hunger::Weak => ORANGE,
+            Hunger::Starving => RED,
+        }
+    }
 }
 
-fn closest_target(tcod: &Tcod, objects: &Vec<Object>, max_range: i32) -> Option<usize> {
-    let mut closest_enemy = None;
-    let mut closest_dist = (max_range + 1) as f32;
+impl std::fmt::Display for Hunger {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Hunger::Satiated => write!(f, "Satiated"),
+            Hunger::NotHungry => write!(f, "Not hungry"),
+            Hunger::Hungry => write!(f, "Hungry"),
+            Hunger::Weak => write!(f, "Weak"),
+            Hunger::Starving => write!(f, "Starving"),
+        }
+    }
+}
 
-    let player_pos = objects[PLAYER].pos();
+impl Item {
+    pub fn fires(self, ammunition: Item) -> bool {
+        matches!(
+            (self, ammunition),
+            (Item::Bow, Item::Arrows) | (Item::Crossbow, Item::Bolts)
+        )
+    }
 
-    let _: Vec<()> = objects
-        .iter()
-        .enumerate()
-        .map(|(id, object)| {
-            if (id != PLAYER)
-                && object.fighter.is_some()
-                && object.ai.is_some()
-                && tcod.fov.is_in_fov(object.x, object.y)
-            {
-                let dist = object.distance_to_point(player_pos);
-                if dist < closest_dist {
-                    closest_enemy = Some(id);
-                    closest_dist = dist;
-                }
-            }
-        })
-        .collect();
+    // identical stackable items share a single inventory slot
+    pub fn is_stackable(self) -> bool {
+        matches!(
+            self,
+            Item::Heal { .. }
+                | Item::ManaPotion
+                | Item::Lightning { .. }
+                | Item::Confuse
+                | Item::Identify
+                | Item::Repair
+                | Item::Recharge
+                | Item::MagicMapping
+                | Item::Teleport
+                | Item::DetectMonsters
+                | Item::RemoveFog
+                | Item::RemoveCurse
+                | Item::HolyWater
+                | Item::Gold
+                | Item::Food { .. }
+                | Item::Ingredient { .. }
+                | Item::Arrows
+                | Item::Bolts
+        )
+    }
 
-    closest_enemy
+    pub fn category(self) -> ItemCategory {
+        match self {
+            Item::Sword
+            | Item::Greatsword
+            | Item::Bow
+            | Item::Crossbow
+            | Item::Arrows
+            | Item::Bolts => ItemCategory::Weapons,
+            Item::Shield
+            | Item::Helmet
+            | Item::BodyArmor
+            | Item::Boots
+            | Item::Cloak
+            | Item::Gloves => ItemCategory::Armor,
+            Item::Ring | Item::Amulet => ItemCategory::Jewelry,
+            Item::Heal { .. } | Item::ManaPotion | Item::HolyWater => ItemCategory::Potions,
+            Item::Lightning { .. }
+            | Item::Fireball { .. }
+            | Item::Confuse
+            | Item::Identify
+            | Item::Repair
+            | Item::Recharge
+            | Item::MagicMapping
+            | Item::Teleport
+            | Item::DetectMonsters
+            | Item::RemoveFog
+            | Item::RemoveCurse => ItemCategory::Scrolls,
+            Item::Wand { .. } => ItemCategory::Wands,
+            Item::Staff | Item::SpellBook { .. } => ItemCategory::Magic,
+            Item::Food { .. } | Item::Corpse { .. } => ItemCategory::Food,
+            Item::Ingredient { .. } => ItemCategory::Ingredients,
+            Item::Bag | Item::Gold | Item::Relic => ItemCategory::Tools,
+        }
+    }
 }
 
-fn choose_target<F>(
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-    filter: &mut F,
-    msg: &str,
-) -> Option<usize>
-where
-    F: FnMut(&Tcod, &&Object) -> bool,
-{
-    let targets: Vec<&Object> = objects.iter().filter(|o| filter(tcod, o)).collect();
+// inventory groups, listed in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+enum ItemCategory {
+    Weapons,
+    Armor,
+    Jewelry,
+    Potions,
+    Scrolls,
+    Wands,
+    Magic,
+    Food,
+    Ingredients,
+    Tools,
+}
 
-    if targets.len() == 0 {
-        return None;
+impl std::fmt::Display for ItemCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ItemCategory::Weapons => "Weapons",
+            ItemCategory::Armor => "Armor",
+            ItemCategory::Jewelry => "Rings and amulets",
+            ItemCategory::Potions => "Potions",
+            ItemCategory::Scrolls => "Scrolls",
+            ItemCategory::Wands => "Wands",
+            ItemCategory::Magic => "Staffs and books",
+            ItemCategory::Food => "Food",
+            ItemCategory::Ingredients => "Ingredients",
+            ItemCategory::Tools => "Tools",
+        };
+        write!(f, "{}", name)
     }
+}
 
-    let target_names: Vec<_> = targets.iter().map(|o| o.name.clone()).collect();
-
-    // do not render the inventory menu
-    tcod.root.clear();
-    render_all(tcod, game, objects, false);
+// a line in a menu, either an option that can be chosen or a header grouping the options below it
+enum MenuLine {
+    Category(String),
+    Choice(String),
+}
 
-    let target_id = menu(msg, &target_names[..], CHOOSE_MENU_WIDTH, tcod);
+fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, tcod: &mut Tcod) -> Option<usize> {
+    let lines: Vec<MenuLine> = options
+        .iter()
+        .map(|option| MenuLine::Choice(option.as_ref().to_string()))
+        .collect();
+    grouped_menu(header, &lines, width, tcod)
+}
 
-    if let Some(target_id) = target_id {
-        for id in 0..objects.len() {
-            if (&objects[id] as *const _) == (targets[target_id] as *const _) {
-                return Some(id);
-            }
+// splits the lines into pages of at most 26 options that fit on the screen
+fn menu_pages(lines: &[MenuLine], max_lines: usize) -> Vec<&[MenuLine]> {
+    let mut pages = vec![];
+    let mut start = 0;
+    let mut choices = 0;
+
+    for (index, line) in lines.iter().enumerate() {
+        let full = index - start >= max_lines
+            || (choices >= MENU_PAGE_SIZE && matches!(line, MenuLine::Choice(_)));
+        if full {
+            pages.push(&lines[start..index]);
+            start = index;
+            choices = 0;
+        }
+        if let MenuLine::Choice(_) = line {
+            choices += 1;
         }
     }
+    pages.push(&lines[start..]);
 
-    None
+    pages
 }
 
-fn cast_lightning(
-    inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    let target = closest_target(tcod, objects, LIGHTNING_RANGE);
-    if let Some(target) = target {
-        let (player, target) = mut_two(PLAYER, target, objects);
-        player.attack(target, game, Some(inventory_id));
-
-        UseResult::UsedUp
-    } else {
-        game.messages.add("You need to choose a target", WHITE);
-        UseResult::Cancelled
-    }
-}
+// returns the index of the chosen option, counting only the options and not the categories
+fn grouped_menu(header: &str, lines: &[MenuLine], width: i32, tcod: &mut Tcod) -> Option<usize> {
+    use tcod::input::KeyCode::*;
 
-fn cast_fireballstaff(
-    inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    let range = if let Some(Item::FireballStaff { range }) = game.inventory[inventory_id].item {
-        range
-    } else {
+    // calculate the total height of the header (after auto-wrap)
+    let header_height = if header.is_empty() {
         0
+    } else {
+        tcod.root
+            .get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
     };
-    let player_pos = objects[PLAYER].pos();
-    let target = choose_target(
-        tcod,
-        game,
-        objects,
-        &mut |tcod: &Tcod, o: &&Object| -> bool {
-            tcod.fov.is_in_fov(o.x, o.y)
-                && o.ai.is_some()
-                && o.distance_to_point(player_pos) <= range as f32
-        },
-        "Choose target for fireball staff",
-    );
-    if let Some(target) = target {
-        let (player, target) = mut_two(PLAYER, target, objects);
-        player.attack(target, game, Some(inventory_id));
+    let max_lines = cmp::max(1, SCREEN_HEIGHT - header_height - 1) as usize;
+    let pages = menu_pages(lines, max_lines);
 
-        UseResult::Used
-    } else {
-        game.messages.add("You need to choose a target", WHITE);
-        UseResult::Cancelled
+    // the first option on every page
+    let mut first_choice = vec![0];
+    for page in &pages {
+        let choices = page
+            .iter()
+            .filter(|line| matches!(line, MenuLine::Choice(_)))
+            .count();
+        first_choice.push(first_choice[first_choice.len() - 1] + choices);
     }
-}
+    let total_choices = first_choice[pages.len()];
 
-fn cast_fireball(
-    inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    let player_pos = objects[PLAYER].pos();
-    let target = choose_target(
-        tcod,
-        game,
-        objects,
-        &mut |tcod: &Tcod, o: &&Object| -> bool {
-            tcod.fov.is_in_fov(o.x, o.y)
-                && o.ai.is_some()
-                && o.distance_to_point(player_pos) <= FIREBALL_RANGE as f32
-        },
-        "Choose target for fireball scroll",
+    // the menu is drawn on top of whatever is on the screen now
+    let mut background = Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+    blit(
+        &tcod.root,
+        (0, 0),
+        (SCREEN_WIDTH, SCREEN_HEIGHT),
+        &mut background,
+        (0, 0),
+        1.0,
+        1.0,
     );
-    if let Some(target) = target {
-        let (player, target) = mut_two(PLAYER, target, objects);
-        player.attack(target, game, Some(inventory_id));
 
-        if let Some(Item::Fireball { charges, damage }) = game.inventory[inventory_id].item {
-            game.inventory[inventory_id].item = Some(Item::Fireball {
-                charges: charges - 1,
-                damage,
-            });
-            if charges > 1 {
-                UseResult::Used
-            } else {
-                UseResult::UsedUp
+    let mut page = 0;
+    let mut selected = 0;
+
+    tcod.key = Default::default();
+
+    loop {
+        let footer_height = if pages.len() > 1 { 1 } else { 0 };
+        let height = pages[page].len() as i32 + header_height + footer_height;
+
+        // create an offscreen console that represents the menu's window
+        let mut window = Offscreen::new(width, height);
+
+        // print the header, with autowrap
+        window.set_default_foreground(WHITE);
+        window.print_rect_ex(
+            0,
+            0,
+            width,
+            height,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            header,
+        );
+
+        // the option each line of the page belongs to
+        let mut line_choices = vec![];
+        let mut choice = first_choice[page];
+        for (row, line) in pages[page].iter().enumerate() {
+            let y = header_height + row as i32;
+            match line {
+                MenuLine::Category(name) => {
+                    window.set_default_foreground(LIGHT_YELLOW);
+                    window.print_ex(0, y, BackgroundFlag::None, TextAlignment::Left, name);
+                    line_choices.push(None);
+                }
+                MenuLine::Choice(text) => {
+                    let menu_letter = (b'a' + (choice - first_choice[page]) as u8) as char;
+                    if choice == selected {
+                        window.set_default_background(DARKER_AZURE);
+                        window.rect(0, y, width, 1, false, BackgroundFlag::Set);
+                    }
+                    window.set_default_foreground(WHITE);
+                    window.print_ex(
+                        0,
+                        y,
+                        BackgroundFlag::None,
+                        TextAlignment::Left,
+                        format!("({}) {}", menu_letter, text),
+                    );
+                    line_choices.push(Some(choice));
+                    choice += 1;
+                }
             }
-        } else {
-            println!("Something wierd happened, this should not happen.");
-            UseResult::Cancelled
         }
-    } else {
-        game.messages.add("You need to choose a target", WHITE);
-        UseResult::Cancelled
-    }
-}
 
-fn cast_confuse(
-    _inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    let player_pos = objects[PLAYER].pos();
-    let target = choose_target(
-        tcod,
-        game,
-        objects,
-        &mut |tcod: &Tcod, o: &&Object| -> bool {
-            tcod.fov.is_in_fov(o.x, o.y)
-                && o.ai.is_some()
-                && o.distance_to_point(player_pos) <= CONFUSED_RANGE as f32
-        },
-        "Choose target for confusion scroll",
-    );
-    if let Some(target) = target {
-        let old_ai = objects[target].ai.take().unwrap_or(Ai::Basic);
-        objects[target].ai = Some(Ai::Confused {
-            previous_ai: Box::new(old_ai),
-            num_turns: CONFUSED_NUM_TURNS,
-        });
-        game.messages.add(
-            format!(
-                "The eyes of {} look vacant, as it starts to stumble around!",
-                objects[target].name
-            ),
-            YELLOW,
+        if pages.len() > 1 {
+            window.set_default_foreground(LIGHT_GREY);
+            window.print_ex(
+                width / 2,
+                height - 1,
+                BackgroundFlag::None,
+                TextAlignment::Center,
+                format!("< page {}/{} >", page + 1, pages.len()),
+            );
+        }
+
+        let x = SCREEN_WIDTH / 2 - width / 2;
+        let y = SCREEN_HEIGHT / 2 - height / 2;
+        blit(
+            &background,
+            (0, 0),
+            (SCREEN_WIDTH, SCREEN_HEIGHT),
+            &mut tcod.root,
+            (0, 0),
+            1.0,
+            1.0,
+        );
+        blit(
+            &window,
+            (0, 0),
+            (width, height),
+            &mut tcod.root,
+            (x, y),
+            1.0,
+            0.7,
         );
 
-        UseResult::UsedUp
-    } else {
-        game.messages.add("You need to choose a target", WHITE);
-        UseResult::Cancelled
-    }
-}
+        tcod.root.flush();
 
-fn cast_mana_potion(
-    inventory_id: usize,
-    _tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    if let Some(fighter) = objects[PLAYER].fighter {
-        if fighter.mana == objects[PLAYER].max_mana(game) {
-            game.messages.add("You are already at full mana", YELLOW);
-            return UseResult::Cancelled;
-        } else {
-            if let Some(Item::ManaPotion) = game.inventory[inventory_id].item {
-                game.messages.add("your arcane energy is bubbling", YELLOW);
-                objects[PLAYER].regenerate_mana(MANA_REGENERATION, game);
+        let previous_page = page;
+        match input::check_for_event(input::KEY_PRESS | input::MOUSE) {
+            Some((_, Event::Key(key))) => match key.code {
+                Text => {
+                    let c = key.printable;
+                    if c == '<' {
+                        page = page.saturating_sub(1);
+                    } else if c == '>' {
+                        page = cmp::min(page + 1, pages.len() - 1);
+                    } else if c.is_alphabetic() {
+                        let index =
+                            first_choice[page] + c.to_ascii_lowercase() as usize - 'a' as usize;
+                        if index < first_choice[page + 1] {
+                            return Some(index);
+                        }
+                        return None;
+                    }
+                }
+                Up if selected > 0 => selected -= 1,
+                Down if selected + 1 < total_choices => selected += 1,
+                Left | PageUp => page = page.saturating_sub(1),
+                Right | PageDown => page = cmp::min(page + 1, pages.len() - 1),
+                Enter if total_choices > 0 => return Some(selected),
+                Enter | Escape => return None,
+                _ => {}
+            },
+            Some((_, Event::Mouse(mouse))) => {
+                tcod.mouse = mouse;
+                let row = mouse.cy as i32 - y - header_height;
+                let inside = mouse.cx as i32 >= x && (mouse.cx as i32) < x + width;
+                let hovered = if inside && row >= 0 && (row as usize) < line_choices.len() {
+                    line_choices[row as usize]
+                } else {
+                    None
+                };
 
-                return UseResult::UsedUp;
+                if let Some(hovered) = hovered {
+                    selected = hovered;
+                    if mouse.lbutton_pressed {
+                        return Some(hovered);
+                    }
+                }
+                if mouse.rbutton_pressed {
+                    return None;
+                }
+                if mouse.wheel_up {
+                    page = page.saturating_sub(1);
+                } else if mouse.wheel_down {
+                    page = cmp::min(page + 1, pages.len() - 1);
+                }
             }
-            println!(
-                "Tried to heal with an item that is not a mana regeneration {:?}",
-                game.inventory[inventory_id]
-            );
+            _ => {}
         }
-    }
-    UseResult::Cancelled
-}
-
-fn cast_heal(
-    inventory_id: usize,
-    _tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> UseResult {
-    if let Some(fighter) = objects[PLAYER].fighter {
-        if fighter.hp == objects[PLAYER].max_hp(game) {
-            game.messages.add("You are already at full heal", YELLOW);
-            return UseResult::Cancelled;
-        } else {
-            if let Some(Item::Heal { amount }) = game.inventory[inventory_id].item {
-                game.messages
-                    .add("your wounds start to feel better", YELLOW);
-                objects[PLAYER].heal(amount, game);
 
-                return UseResult::UsedUp;
+        // keep the selection on the visible page
+        if page != previous_page {
+            selected = first_choice[page];
+        } else if total_choices > 0 {
+            while selected < first_choice[page] {
+                page -= 1;
+            }
+            while selected >= first_choice[page + 1] {
+                page += 1;
             }
-            println!(
-                "Tried to heal with an item that is not a heal {:?}",
-                game.inventory[inventory_id]
-            );
         }
     }
-    UseResult::Cancelled
 }
 
-fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    if game.inventory.len() >= 26 {
-        game.messages.add(
-            format!(
-                "Cannot pick up {} since inventory is full",
-                objects[object_id].name
-            ),
-            YELLOW,
-        );
-    } else {
-        let item = objects.swap_remove(object_id);
-        game.messages
-            .add(format!("You picked up {}", item.name), YELLOW);
-        let index = game.inventory.len();
-        let slot = item.equipment.map(|e| e.slot);
-        game.inventory.push(item);
+fn inventory_menu(game: &Game, header: &str, tcod: &mut Tcod) -> Option<usize> {
+    let inventory = &game.inventory;
+    if inventory.is_empty() {
+        menu(header, &["Inventory is empty"], INVENTORY_WIDTH, tcod);
+        return None;
+    }
 
-        if let Some(slot) = slot {
-            if get_equipped_in_slot(slot, &game.inventory).is_none() {
-                game.inventory[index].equip(&mut game.messages);
+    // list the items grouped by category, remembering where each one is in the inventory
+    let mut order: Vec<usize> = (0..inventory.len()).collect();
+    order.sort_by_key(|&id| inventory[id].item.map(Item::category));
+    let names: Vec<String> = order
+        .iter()
+        .map(|&id| &inventory[id])
+        .map(|item| {
+            let mut name = item.display_name(game);
+            if item.count > 1 {
+                name = format!("{} ({})", name, item.count);
+            }
+            if let Some(equipment) = item.equipment {
+                if !equipment.identified {
+                    name = format!("{} [unidentified]", name);
+                } else if !equipment.bonuses().is_empty() {
+                    name = format!("{} [{}]", name, equipment.bonuses());
+                }
+            }
+            match item.equipment {
+                Some(equipment) if equipment.equipped => {
+                    format!("{} (on {})", name, equipment.slot)
+                }
+                _ => name,
+            }
+        })
+        .collect();
+
+    let mut lines = vec![];
+    let mut category = None;
+    for (&id, name) in order.iter().zip(names) {
+        let item_category = inventory[id].item.map(Item::category);
+        if item_category != category {
+            if let Some(item_category) = item_category {
+                lines.push(MenuLine::Category(item_category.to_string()));
             }
+            category = item_category;
         }
+        lines.push(MenuLine::Choice(name));
     }
+
+    grouped_menu(header, &lines, INVENTORY_WIDTH, tcod).map(|choice| order[choice])
 }
 
-fn drop_item(inventory_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
-    let mut item = game.inventory.remove(inventory_id);
-    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
-    game.messages
-        .add(format!("You dropped a {}", item.name), YELLOW);
-    if item.equipment.is_some() {
-        item.dequip(&mut game.messages);
-    }
-    objects.push(item);
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Equipment {
+    slot: Slot,
+    equipped: bool,
+    // equipment from older saves has no hidden properties
+    #[serde(default = "already_identified")]
+    identified: bool,
+    power_bonus: i32,
+    defense_bonus: i32,
+    max_hp_bonus: i32,
+    max_mana_bonus: i32,
+    spellcast_bonus: i32,
+    #[serde(default)]
+    accuracy_bonus: i32,
+    #[serde(default)]
+    evasion_bonus: i32,
+    #[serde(default)]
+    critical_bonus: i32,
+    #[serde(default)]
+    hp_regen_bonus: i32,
+    #[serde(default)]
+    mana_regen_bonus: i32,
+    #[serde(default)]
+    range: i32,
+    #[serde(default)]
+    on_hit: Option<OnHit>,
+    #[serde(default)]
+    two_handed: bool,
+    #[serde(default = "full_durability")]
+    durability: i32,
+    #[serde(default = "full_durability")]
+    max_durability: i32,
+    // heavy gear can only be used with enough of an attribute
+    #[serde(default)]
+    requirement: Option<(Attribute, i32)>,
+    // indices into PREFIXES and SUFFIXES
+    #[serde(default)]
+    prefix: Option<usize>,
+    #[serde(default)]
+    suffix: Option<usize>,
 }
 
-fn toggle_equipment(
-    inventory_id: usize,
-    _tcod: &mut Tcod,
-    game: &mut Game,
-    _objects: &mut Vec<Object>,
-) -> UseResult {
-    let equipment = match game.inventory[inventory_id].equipment {
-        Some(equipment) => equipment,
-        None => return UseResult::Cancelled,
-    };
-    if equipment.equipped {
-        game.inventory[inventory_id].dequip(&mut game.messages);
-    } else {
-        if let Some(current) = get_equipped_in_slot(equipment.slot, &game.inventory) {
-            game.inventory[current].dequip(&mut game.messages);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Class {
+    #[default]
+    Warrior,
+    Mage,
+    Rogue,
+    Ranger,
+}
+
+impl Class {
+    const ALL: [Class; 4] = [Class::Warrior, Class::Mage, Class::Rogue, Class::Ranger];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Class::Warrior => "tough and strong, fights with sword and shield",
+            Class::Mage => "frail, but starts with a staff and two spells",
+            Class::Rogue => "quick and hard to hit, strikes true with a dagger",
+            Class::Ranger => "fights from afar with bow and arrows",
         }
-        game.inventory[inventory_id].equip(&mut game.messages);
     }
 
-    UseResult::Used
-}
+    pub fn attributes(self) -> Attributes {
+        let (strength, dexterity, constitution, intelligence, willpower) = match self {
+            Class::Warrior => (14, 10, 14, 8, 8),
+            Class::Mage => (8, 10, 8, 16, 14),
+            Class::Rogue => (10, 16, 10, 10, 10),
+            Class::Ranger => (10, 14, 12, 10, 10),
+        };
+        Attributes {
+            strength,
+            dexterity,
+            constitution,
+            intelligence,
+            willpower,
+        }
+    }
 
-enum UseResult {
-    UsedUp,
-    Used,
-    Cancelled,
-}
+    // the stats a new character of the class starts with, on top of what its attributes give
+    pub fn fighter(self) -> Fighter {
+        let (max_hp, defense, power, max_mana, spellcast, accuracy, evasion) = match self {
+            Class::Warrior => (90, 0, 1, 30, 1, 1, 0),
+            Class::Mage => (100, 1, 2, 0, 2, 0, 1),
+            Class::Rogue => (90, 1, 2, 20, 2, -1, 0),
+            Class::Ranger => (80, 0, 2, 20, 2, 0, 0),
+        };
+        Fighter {
+            base_max_hp: max_hp,
+            hp: max_hp,
+            base_defense: defense,
+            base_power: power,
+            xp: 0,
+            base_max_mana: max_mana,
+            base_spellcast_modifier: spellcast,
+            base_accuracy: accuracy,
+            base_evasion: evasion,
+            mana: max_mana,
+            hp_regen_progress: 0.0,
+            mana_regen_progress: 0.0,
+            attributes: self.attributes(),
+            on_death: DeathCallback::Player,
+        }
+    }
 
-fn use_item(
-    inventory_id: usize,
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) -> PlayerAction {
-    use Item::*;
+    pub fn spells(self) -> Vec<Spell> {
+        match self {
+            Class::Warrior => vec![],
+            Class::Mage => vec![Spell::MagicMissile, Spell::Confuse],
+            Class::Rogue => vec![Spell::MagicMissile],
+            Class::Ranger => vec![Spell::Heal],
+        }
+    }
 
-    if let Some(item) = game.inventory[inventory_id].item {
-        let on_use = match item {
-            Heal { .. } => cast_heal,
-            ManaPotion => cast_mana_potion,
-            Lightning { .. } => cast_lightning,
-            Fireball { .. } => cast_fireball,
-            FireballStaff { .. } => cast_fireballstaff,
-            Confuse => cast_confuse,
-            Sword => toggle_equipment,
-            Shield => toggle_equipment,
-            Helmet => toggle_equipment,
-            BodyArmor => toggle_equipment,
+    pub fn starting_items(self) -> Vec<Object> {
+        let item = |kind, count| {
+            let mut item = plain_item(kind, 0, 0, 0);
+            item.count = count;
+            item
         };
-        match on_use(inventory_id, tcod, game, objects) {
-            UseResult::UsedUp => {
-                game.messages.add(
-                    format!("{} is used up", game.inventory[inventory_id].name),
-                    YELLOW,
-                );
-                game.inventory.remove(inventory_id);
-                PlayerAction::TookTurn
-            }
-            UseResult::Cancelled => {
-                game.messages.add("Cancelled", WHITE);
-                PlayerAction::DidntTakeTurn
+        let food = item(Item::Food { nutrition: 0 }, 2);
+        match self {
+            Class::Warrior => vec![item(Item::Sword, 1), item(Item::Shield, 1), food],
+            Class::Mage => vec![item(Item::Staff, 1), item(Item::ManaPotion, 2), food],
+            Class::Rogue => {
+                let mut dagger = Object::new(0, 0, '-', "dagger", SKY, false);
+                dagger.item = Some(Item::Sword);
+                dagger.equipment = Some(Equipment {
+                    equipped: true,
+                    identified: true,
+                    slot: Slot::LeftHand,
+                    max_hp_bonus: 0,
+                    defense_bonus: 0,
+                    power_bonus: 2,
+                    range: 0,
+                    max_mana_bonus: 0,
+                    spellcast_bonus: 0,
+                    accuracy_bonus: 0,
+                    evasion_bonus: 0,
+                    critical_bonus: 5,
+                    hp_regen_bonus: 0,
+                    mana_regen_bonus: 0,
+                    on_hit: None,
+                    two_handed: false,
+                    durability: EQUIPMENT_DURABILITY,
+                    max_durability: EQUIPMENT_DURABILITY,
+                    requirement: None,
+                    prefix: None,
+                    suffix: None,
+                });
+                vec![dagger, item(Item::Heal { amount: 0 }, 2), food]
             }
-            UseResult::Used => PlayerAction::TookTurn,
+            Class::Ranger => vec![item(Item::Bow, 1), item(Item::Arrows, 30), food],
+        }
+    }
+
+    // the stats the class can raise when leveling up
+    pub fn level_ups(self) -> &'static [LevelUp] {
+        use LevelUp::*;
+        match self {
+            Class::Warrior => &[Constitution, Strength, Dexterity, Precision],
+            Class::Mage => &[Constitution, Intelligence, Willpower, ArcaneStudy],
+            Class::Rogue => &[Constitution, Strength, Dexterity, Precision, Reflexes],
+            Class::Ranger => &[Constitution, Dexterity, Precision, Reflexes, Intelligence],
         }
-    } else {
-        game.messages.add(
-            format!("the {} cannot be used", game.inventory[inventory_id].name),
-            WHITE,
-        );
-        PlayerAction::DidntTakeTurn
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-struct Fighter {
-    base_max_hp: i32,
-    hp: i32,
-    base_defense: i32,
-    base_power: i32,
-    mana: i32,
-    base_max_mana: i32,
-    base_spellcast_modifier: i32,
-    xp: i32,
-    on_death: DeathCallback,
+impl std::fmt::Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Class::Warrior => write!(f, "warrior"),
+            Class::Mage => write!(f, "mage"),
+            Class::Rogue => write!(f, "rogue"),
+            Class::Ranger => write!(f, "ranger"),
+        }
+    }
 }
 
+// perks are learned on level up and come in small trees, each perk needing the one before it
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-enum DeathCallback {
-    Player,
-    Monster,
-    MonsterArcher,
+enum Perk {
+    Cleave,
+    Bloodlust,
+    SpellPenetration,
+    ManaSurge,
+    Dodge,
+    Riposte,
+    PotentPotions,
+    IronStomach,
 }
 
-impl DeathCallback {
-    fn callback(self, object: &mut Object, game: &mut Game) {
-        use DeathCallback::*;
-        let callback: fn(&mut Object, &mut Game) = match self {
-            Player => player_death,
-            Monster => monster_death,
-            MonsterArcher => archer_death,
-        };
-        callback(object, game);
+impl Perk {
+    const ALL: [Perk; 8] = [
+        Perk::Cleave,
+        Perk::Bloodlust,
+        Perk::SpellPenetration,
+        Perk::ManaSurge,
+        Perk::Dodge,
+        Perk::Riposte,
+        Perk::PotentPotions,
+        Perk::IronStomach,
+    ];
+    const TREES: [&'static str; 4] = ["Might", "Sorcery", "Finesse", "Herbalism"];
+
+    pub fn tree(self) -> &'static str {
+        match self {
+            Perk::Cleave | Perk::Bloodlust => "Might",
+            Perk::SpellPenetration | Perk::ManaSurge => "Sorcery",
+            Perk::Dodge | Perk::Riposte => "Finesse",
+            Perk::PotentPotions | Perk::IronStomach => "Herbalism",
+        }
+    }
+
+    pub fn prerequisite(self) -> Option<Perk> {
+        match self {
+            Perk::Bloodlust => Some(Perk::Cleave),
+            Perk::ManaSurge => Some(Perk::SpellPenetration),
+            Perk::Riposte => Some(Perk::Dodge),
+            Perk::IronStomach => Some(Perk::PotentPotions),
+            _ => None,
+        }
+    }
+
+    // the character level needed to learn the perk
+    pub fn min_level(self) -> i32 {
+        match self.prerequisite() {
+            Some(_) => PERK_TIER_LEVEL,
+            None => 0,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Perk::Cleave => "a killing blow carries on to another enemy",
+            Perk::Bloodlust => "melee kills heal you",
+            Perk::SpellPenetration => "magic ignores half of the defense",
+            Perk::ManaSurge => "spells cost a quarter less mana",
+            Perk::Dodge => "sometimes avoid melee attacks entirely",
+            Perk::Riposte => "strike back after dodging",
+            Perk::PotentPotions => "potions work half again as well",
+            Perk::IronStomach => "rotten corpses don't make you sick",
+        }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-enum Ai {
-    Basic,
-    Confused {
-        previous_ai: Box<Ai>,
-        num_turns: i32,
-    },
-    Archer {
-        range: i32,
-    },
+impl std::fmt::Display for Perk {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Perk::Cleave => write!(f, "cleave"),
+            Perk::Bloodlust => write!(f, "bloodlust"),
+            Perk::SpellPenetration => write!(f, "spell penetration"),
+            Perk::ManaSurge => write!(f, "mana surge"),
+            Perk::Dodge => write!(f, "dodge"),
+            Perk::Riposte => write!(f, "riposte"),
+            Perk::PotentPotions => write!(f, "potent potions"),
+            Perk::IronStomach => write!(f, "iron stomach"),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
-    Exit,
+enum LevelUp {
+    Constitution,
+    Strength,
+    Dexterity,
+    Precision,
+    Reflexes,
+    Intelligence,
+    Willpower,
+    ArcaneStudy,
+    Perk(Perk),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-enum Item {
-    Heal { amount: i32 },
-    Lightning { damage: i32 },
-    Fireball { charges: i32, damage: i32 },
+enum Spell {
+    MagicMissile,
+    Heal,
     Confuse,
-    Sword,
-    Shield,
-    Helmet,
-    BodyArmor,
-    ManaPotion,
-    FireballStaff { range: i32 },
+    Lightning,
+    Fireball,
 }
 
-fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, tcod: &mut Tcod) -> Option<usize> {
-    assert!(
-        options.len() <= 26,
-        "Cannot have a menu with more than 26 options."
-    );
-
-    // calculate the total height of the header (after auto-wrap) and one line per option
-    let header_height = if header.is_empty() {
-        0
-    } else {
-        tcod.root
-            .get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
-    };
-    let height = options.len() as i32 + header_height;
+impl Spell {
+    const ALL: [Spell; 5] = [
+        Spell::MagicMissile,
+        Spell::Heal,
+        Spell::Confuse,
+        Spell::Lightning,
+        Spell::Fireball,
+    ];
 
-    // create an offscreen console that represents the menu's window
-    let mut window = Offscreen::new(width, height);
+    pub fn mana_cost(self) -> i32 {
+        match self {
+            Spell::MagicMissile => 5,
+            Spell::Heal => 12,
+            Spell::Confuse => 8,
+            Spell::Lightning => 15,
+            Spell::Fireball => 25,
+        }
+    }
 
-    // print the header, with autowrap
-    window.set_default_foreground(WHITE);
-    window.print_rect_ex(
-        0,
-        0,
-        width,
-        height,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        header,
-    );
+    // the spellcasting needed to cast the spell without any risk of failing
+    pub fn difficulty(self) -> i32 {
+        match self {
+            Spell::MagicMissile => 0,
+            Spell::Heal => 3,
+            Spell::Confuse => 4,
+            Spell::Lightning => 7,
+            Spell::Fireball => 10,
+        }
+    }
 
-    for (index, option_text) in options.iter().enumerate() {
-        let menu_letter = (b'a' + index as u8) as char;
-        let text = format!("({}) {}", menu_letter, option_text.as_ref());
-        window.print_ex(
-            0,
-            header_height + index as i32,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            text,
-        );
+    // the first dungeon level where books of the spell are found
+    pub fn min_level(self) -> u32 {
+        match self {
+            Spell::MagicMissile => 0,
+            Spell::Heal => 1,
+            Spell::Confuse => 2,
+            Spell::Lightning => 4,
+            Spell::Fireball => 6,
+        }
     }
 
-    let x = SCREEN_WIDTH / 2 - width / 2;
-    let y = SCREEN_HEIGHT / 2 - height / 2;
-    blit(
-        &window,
-        (0, 0),
-        (width, height),
-        &mut tcod.root,
-        (x, y),
-        1.0,
-        0.7,
-    );
+    // damage, healing or number of turns, depending on the spell
+    pub fn power(self, spellcast: i32) -> i32 {
+        match self {
+            Spell::MagicMissile => 4 + spellcast,
+            Spell::Heal => 10 + 3 * spellcast,
+            Spell::Confuse => 5 + spellcast,
+            Spell::Lightning => 10 + 3 * spellcast,
+            Spell::Fireball => 8 + 2 * spellcast,
+        }
+    }
 
-    tcod.root.flush();
+    pub fn failure_chance(self, spellcast: i32) -> f32 {
+        let chance =
+            SPELL_BASE_FAILURE + (self.difficulty() - spellcast) as f32 * SPELL_FAILURE_PER_POINT;
+        chance.clamp(0.0, SPELL_MAX_FAILURE)
+    }
+}
 
-    tcod.key = Default::default();
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum WandEffect {
+    Digging,
+    Slow,
+    Teleport,
+    Lightning,
+}
 
-    while match tcod::input::check_for_event(input::KEY_PRESS) {
-        Some((
-            _,
-            Event::Key(Key {
-                code: input::KeyCode::Text,
-                printable: c,
-                ..
-            }),
-        )) => {
-            if c.is_alphabetic() {
-                let index = c.to_ascii_lowercase() as usize - 'a' as usize;
-                if index < options.len() {
-                    return Some(index);
-                }
-            }
-            false
+impl std::fmt::Display for WandEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            WandEffect::Digging => write!(f, "digging"),
+            WandEffect::Slow => write!(f, "slowness"),
+            WandEffect::Teleport => write!(f, "teleportation"),
+            WandEffect::Lightning => write!(f, "lightning"),
         }
-        Some((
-            _,
-            Event::Key(Key {
-                code: input::KeyCode::Escape,
-                ..
-            }),
-        )) => false,
-        _ => true,
-    } {}
+    }
+}
 
-    None
+impl std::fmt::Display for Spell {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Spell::MagicMissile => write!(f, "magic missile"),
+            Spell::Heal => write!(f, "heal"),
+            Spell::Confuse => write!(f, "confusion"),
+            Spell::Lightning => write!(f, "lightning"),
+            Spell::Fireball => write!(f, "fireball"),
+        }
+    }
+}
 
-    //while key.is_none() {
-    //key = match tcod::input::check_for_event(input::KEY_PRESS) {
-    //Some((_, Event::Key(k))) => Some(k),
-    //_ => None,
-    //};
-    //}
+impl Equipment {
+    // the equipment as it performs in its current condition, losing bonuses below half durability
+    pub fn worn(&self) -> Equipment {
+        let factor = (0.5 + self.durability as f32 / self.max_durability as f32).min(1.0);
+        let scale = |bonus: i32| {
+            if bonus > 0 {
+                (bonus as f32 * factor).round() as i32
+            } else {
+                bonus
+            }
+        };
+        Equipment {
+            power_bonus: scale(self.power_bonus),
+            defense_bonus: scale(self.defense_bonus),
+            max_hp_bonus: scale(self.max_hp_bonus),
+            max_mana_bonus: scale(self.max_mana_bonus),
+            spellcast_bonus: scale(self.spellcast_bonus),
+            accuracy_bonus: scale(self.accuracy_bonus),
+            evasion_bonus: scale(self.evasion_bonus),
+            critical_bonus: scale(self.critical_bonus),
+            hp_regen_bonus: scale(self.hp_regen_bonus),
+            mana_regen_bonus: scale(self.mana_regen_bonus),
+            ..*self
+        }
+    }
 
-    //let key = tcod.root.wait_for_keypress(true);
+    pub fn condition(&self) -> &'static str {
+        let condition = self.durability as f32 / self.max_durability as f32;
+        if condition >= 1.0 {
+            "pristine"
+        } else if condition >= 0.5 {
+            "worn"
+        } else if condition >= 0.25 {
+            "damaged"
+        } else {
+            "badly damaged"
+        }
+    }
 
-    //match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-    //Some((_, Event::Mouse(m))) => tcod.mouse = m,
-    //Some((_, Event::Key(k))) => tcod.key = k,
-    //_ => tcod.key = Default::default(),
-    //}
+    // the affixes give away what the item does, so they only show once it is identified
+    pub fn affixed_name(&self, name: String) -> String {
+        if !self.identified {
+            return name;
+        }
+        let name = match self.prefix {
+            Some(prefix) => format!("{} {}", PREFIXES[prefix].name, name),
+            None => name,
+        };
+        match self.suffix {
+            Some(suffix) => format!("{} {}", name, SUFFIXES[suffix].name),
+            None => name,
+        }
+    }
 
-    //tcod.key = Default::default();
-    //if let Some(key) = key {
-    //k
-}
+    pub fn usable_with(&self, attributes: &Attributes) -> bool {
+        self.requirement
+            .is_none_or(|(attribute, value)| attributes.get(attribute) >= value)
+    }
 
-fn inventory_menu(inventory: &Vec<Object>, header: &str, tcod: &mut Tcod) -> Option<usize> {
-    let options = if inventory.len() == 0 {
-        vec!["Inventory is empty".into()]
-    } else {
-        inventory
+    pub fn bonuses(&self) -> String {
+        let bonuses = [
+            (self.power_bonus, "attack"),
+            (self.defense_bonus, "defense"),
+            (self.max_hp_bonus, "HP"),
+            (self.max_mana_bonus, "mana"),
+            (self.spellcast_bonus, "spellcasting"),
+            (self.accuracy_bonus, "accuracy"),
+            (self.evasion_bonus, "evasion"),
+            (self.critical_bonus, "critical"),
+            (self.hp_regen_bonus, "% HP regeneration"),
+            (self.mana_regen_bonus, "% mana regeneration"),
+        ];
+
+        let mut bonuses: Vec<String> = bonuses
             .iter()
-            .map(|item| match item.equipment {
-                Some(equipment) if equipment.equipped => {
-                    format!("{} (on {})", item.name, equipment.slot)
-                }
-                _ => item.name.clone(),
-            })
-            .collect()
-    };
-
-    let inventory_index = menu(header, &options, INVENTORY_WIDTH, tcod);
-
-    if inventory.len() > 0 {
-        inventory_index
-    } else {
-        None
+            .filter(|(bonus, _)| *bonus != 0)
+            .map(|(bonus, name)| format!("{:+} {}", bonus, name))
+            .collect();
+        if let Some(on_hit) = self.on_hit {
+            bonuses.push(on_hit.to_string());
+        }
+        if let Some((attribute, value)) = self.requirement {
+            bonuses.push(format!("needs {} {}", value, attribute));
+        }
+        bonuses.join(", ")
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-struct Equipment {
-    slot: Slot,
-    equipped: bool,
-    power_bonus: i32,
-    defense_bonus: i32,
-    max_hp_bonus: i32,
-    max_mana_bonus: i32,
-    spellcast_bonus: i32,
-    mana_usage: i32,
-}
-
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 enum Slot {
     LeftHand,
     RightHand,
     Head,
     Body,
+    Ammunition,
+    LeftRing,
+    RightRing,
+    Neck,
+    Feet,
+    Back,
+    Hands,
 }
 
 impl std::fmt::Display for Slot {
@@ -1417,11 +5688,18 @@ impl std::fmt::Display for Slot {
             Slot::RightHand => write!(f, "right hand"),
             Slot::Head => write!(f, "head"),
             Slot::Body => write!(f, "body"),
+            Slot::Ammunition => write!(f, "quiver"),
+            Slot::LeftRing => write!(f, "left ring"),
+            Slot::RightRing => write!(f, "right ring"),
+            Slot::Neck => write!(f, "neck"),
+            Slot::Feet => write!(f, "feet"),
+            Slot::Back => write!(f, "back"),
+            Slot::Hands => write!(f, "hands"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct Object {
     x: i32,
     y: i32,
@@ -1432,10 +5710,55 @@ struct Object {
     alive: bool,
     always_visible: bool,
     level: i32,
+    #[serde(default = "one")]
+    count: i32,
     fighter: Option<Fighter>,
     ai: Option<Ai>,
     item: Option<Item>,
     equipment: Option<Equipment>,
+    #[serde(default)]
+    blessing: Blessing,
+    #[serde(default)]
+    container: Option<Container>,
+    #[serde(default)]
+    anvil: Option<Anvil>,
+    // shop stock, which has to be paid for
+    #[serde(default)]
+    for_sale: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Anvil {
+    cracked: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Container {
+    contents: Vec<Object>,
+    // how many stacks fit inside
+    capacity: usize,
+    locked: bool,
+    trapped: bool,
+}
+
+impl Container {
+    pub fn has_room_for(&self, item: &Object) -> bool {
+        item.container.is_none()
+            && (self.contents.len() < self.capacity
+                || self.contents.iter().any(|other| other.stacks_with(item)))
+    }
+
+    // puts the item in, merging it with an identical stack
+    pub fn store(&mut self, item: Object) {
+        match self
+            .contents
+            .iter()
+            .position(|other| other.stacks_with(&item))
+        {
+            Some(stack_id) => self.contents[stack_id].count += item.count,
+            None => self.contents.push(item),
+        }
+    }
 }
 
 impl Object {
@@ -1448,21 +5771,79 @@ impl Object {
             name: name.into(),
             blocks,
             level: 0,
+            count: 1,
             always_visible: false,
             alive: false,
             fighter: None,
             ai: None,
             item: None,
             equipment: None,
+            blessing: Blessing::Uncursed,
+            container: None,
+            anvil: None,
+            for_sale: false,
+        }
+    }
+
+    pub fn stacks_with(&self, other: &Object) -> bool {
+        let unequipped = |e: Equipment| Equipment {
+            equipped: false,
+            ..e
+        };
+        self.item.is_some_and(|item| item.is_stackable())
+            && self.name == other.name
+            && self.item == other.item
+            && self.blessing == other.blessing
+            && self.equipment.map(unequipped) == other.equipment.map(unequipped)
+    }
+
+    pub fn display_name(&self, game: &Game) -> String {
+        let name = if game.is_identified(&self.name) {
+            self.name.clone()
+        } else {
+            game.appearances[&self.name].clone()
+        };
+        let name = match self.item {
+            Some(Item::Wand {
+                charges,
+                charges_known: true,
+                ..
+            }) => format!("{} ({} charges)", name, charges),
+            _ => name,
+        };
+        let name = match self.equipment {
+            Some(equipment) => equipment.affixed_name(name),
+            None => name,
+        };
+        match self.blessing {
+            Blessing::Uncursed => name,
+            _ if !self.blessing_known(game) => name,
+            blessing => format!("{} {}", blessing, name),
         }
     }
 
+    // curses and blessings show once the item itself is identified
+    pub fn blessing_known(&self, game: &Game) -> bool {
+        match self.equipment {
+            Some(equipment) => equipment.identified,
+            None => game.is_identified(&self.name),
+        }
+    }
+
+    // cursed equipment can't be taken off once it is on
+    pub fn is_stuck(&self) -> bool {
+        self.blessing == Blessing::Cursed && self.equipment.is_some_and(|e| e.equipped)
+    }
+
     pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
         if let Some(fighter) = self.fighter.as_mut() {
             if damage > 0 {
                 fighter.hp -= damage;
             }
         }
+        if damage > 0 {
+            anger(self, "at being hurt", game);
+        }
         if let Some(fighter) = self.fighter {
             if fighter.hp <= 0 {
                 self.alive = false;
@@ -1478,7 +5859,7 @@ impl Object {
             game.inventory
                 .iter()
                 .filter(|item| item.equipment.map_or(false, |e| e.equipped))
-                .map(|item| item.equipment.unwrap())
+                .map(|item| item.equipment.unwrap().worn())
                 .collect()
         } else {
             vec![]
@@ -1507,80 +5888,156 @@ impl Object {
         }
     }
 
+    // restores a fraction of a point every turn, the rest is kept until it adds up to whole points
+    pub fn regenerate(&mut self, game: &Game) {
+        let equipment = self.get_all_equipped(game);
+        let hp_bonus: i32 = equipment.iter().map(|e| e.hp_regen_bonus).sum();
+        let mana_bonus: i32 = equipment.iter().map(|e| e.mana_regen_bonus).sum();
+
+        let hp_rate = self.max_hp(game) as f32 * HP_REGEN_FACTOR * (100 + hp_bonus) as f32 / 100.0;
+        let mana_rate = (self.max_mana(game) as f32 * MANA_REGEN_FACTOR
+            + self.spellcast(game) as f32 * MANA_REGEN_PER_SPELLCAST)
+            * (100 + mana_bonus) as f32
+            / 100.0;
+
+        let (hp, mana) = match self.fighter {
+            Some(ref mut fighter) => {
+                fighter.hp_regen_progress += hp_rate.max(0.0);
+                fighter.mana_regen_progress += mana_rate.max(0.0);
+                let hp = fighter.hp_regen_progress.floor();
+                let mana = fighter.mana_regen_progress.floor();
+                fighter.hp_regen_progress -= hp;
+                fighter.mana_regen_progress -= mana;
+                (hp as i32, mana as i32)
+            }
+            None => return,
+        };
+
+        self.heal(hp, game);
+        self.regenerate_mana(mana, game);
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.fighter
+            .map_or(Attributes::average(), |fighter| fighter.attributes)
+    }
+
     pub fn max_hp(&self, game: &Game) -> i32 {
         let base_max_hp = self.fighter.map_or(0, |f| f.base_max_hp);
+        let constitution = self.attributes().excess(Attribute::Constitution);
         let bonus: i32 = self
             .get_all_equipped(game)
             .iter()
             .map(|e| e.max_hp_bonus)
             .sum();
 
-        base_max_hp + bonus
+        base_max_hp + constitution * HP_PER_CONSTITUTION + bonus
     }
 
     pub fn max_mana(&self, game: &Game) -> i32 {
         let base_max_mana = self.fighter.map_or(0, |f| f.base_max_mana);
+        let willpower = self.attributes().excess(Attribute::Willpower);
         let bonus: i32 = self
             .get_all_equipped(game)
             .iter()
             .map(|e| e.max_mana_bonus)
             .sum();
 
-        base_max_mana + bonus
+        base_max_mana + willpower * MANA_PER_WILLPOWER + bonus
     }
 
-    pub fn spellcast_only(&self, game: &Game) -> i32 {
-        let mut mana = self.fighter.map_or(0, |f| f.mana);
+    pub fn spellcast(&self, game: &Game) -> i32 {
         let base_spellcast = self.fighter.map_or(0, |f| f.base_spellcast_modifier);
-        let mut bonus = 0;
-
-        for e in self.get_all_equipped(game) {
-            if e.mana_usage <= mana {
-                mana -= e.mana_usage;
-                bonus += e.spellcast_bonus;
-            }
-        }
+        let intelligence = self.attributes().modifier(Attribute::Intelligence);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.spellcast_bonus)
+            .sum();
 
-        base_spellcast + bonus
+        base_spellcast + intelligence + bonus
     }
 
-    pub fn spellcast(&mut self, game: &Game) -> i32 {
-        let mut mana = self.fighter.map_or(0, |f| f.mana);
-        let base_spellcast = self.fighter.map_or(0, |f| f.base_spellcast_modifier);
-        let mut bonus = 0;
-
-        for e in self.get_all_equipped(game) {
-            if e.mana_usage <= mana {
-                mana -= e.mana_usage;
-                bonus += e.spellcast_bonus;
-            }
-        }
+    pub fn power(&self, game: &Game) -> i32 {
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .filter(|e| e.range == 0 && e.slot != Slot::Ammunition)
+            .map(|e| e.power_bonus)
+            .sum();
 
-        self.fighter.as_mut().map(|f| f.mana = mana);
+        self.unarmed_power(game) + bonus
+    }
 
-        base_spellcast + bonus
+    // the power of a blow before any weapon is added to it
+    pub fn unarmed_power(&self, game: &Game) -> i32 {
+        let base_power = self.fighter.map_or(0, |f| f.base_power);
+        let strength = self.attributes().modifier(Attribute::Strength);
+        base_power + strength - self.hunger_penalty(game)
     }
 
-    pub fn power(&self, game: &Game) -> i32 {
+    pub fn ranged_power(&self, game: &Game) -> i32 {
         let base_power = self.fighter.map_or(0, |f| f.base_power);
+        let dexterity = self.attributes().modifier(Attribute::Dexterity);
         let bonus: i32 = self
             .get_all_equipped(game)
             .iter()
+            .filter(|e| e.range > 0 || e.slot == Slot::Ammunition)
             .map(|e| e.power_bonus)
             .sum();
 
-        base_power + bonus
+        base_power + dexterity + bonus - self.hunger_penalty(game)
     }
 
     pub fn defense(&self, game: &Game) -> i32 {
         let base_defense = self.fighter.map_or(0, |f| f.base_defense);
+        let constitution = self.attributes().modifier(Attribute::Constitution);
         let bonus: i32 = self
             .get_all_equipped(game)
             .iter()
             .map(|e| e.defense_bonus)
             .sum();
 
-        base_defense + bonus
+        base_defense + constitution + bonus
+    }
+
+    pub fn accuracy(&self, game: &Game) -> i32 {
+        let base_accuracy = self.fighter.map_or(0, |f| f.base_accuracy);
+        let dexterity = self.attributes().modifier(Attribute::Dexterity);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.accuracy_bonus)
+            .sum();
+
+        base_accuracy + dexterity + bonus - self.hunger_penalty(game)
+    }
+
+    pub fn hunger_penalty(&self, game: &Game) -> i32 {
+        if self.name == "player" {
+            Hunger::from_nutrition(game.nutrition).penalty()
+        } else {
+            0
+        }
+    }
+
+    pub fn evasion(&self, game: &Game) -> i32 {
+        let base_evasion = self.fighter.map_or(0, |f| f.base_evasion);
+        let dexterity = self.attributes().modifier(Attribute::Dexterity);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.evasion_bonus)
+            .sum();
+
+        base_evasion + dexterity + bonus
+    }
+
+    pub fn critical(&self, game: &Game) -> i32 {
+        self.get_all_equipped(game)
+            .iter()
+            .map(|e| e.critical_bonus)
+            .sum()
     }
 
     pub fn equip(&mut self, messages: &mut Messages) {
@@ -1608,13 +6065,24 @@ impl Object {
         }
     }
 
-    pub fn dequip(&mut self, messages: &mut Messages) {
+    pub fn dequip(&mut self, messages: &mut Messages) -> bool {
         if self.item.is_none() {
             messages.add(
                 format!("Can't dequip {:?} because it is not an Item", self),
                 WHITE,
             );
-            return;
+            return false;
+        }
+        if self.is_stuck() {
+            messages.add(
+                format!(
+                    "You can't remove the {}, it is cursed and stuck to your {}",
+                    self.name,
+                    self.equipment.unwrap().slot
+                ),
+                ORANGE,
+            );
+            return false;
         }
         if let Some(ref mut equipment) = self.equipment {
             if equipment.equipped {
@@ -1624,56 +6092,152 @@ impl Object {
                     YELLOW,
                 );
             }
+            true
         } else {
             messages.add(
                 format!("Cant deequip {:?} because it is not an Equipment", self),
                 WHITE,
             );
+            false
         }
     }
 
-    pub fn level_up(tcod: &mut Tcod, _game: &mut Game, objects: &mut [Object]) {
+    pub fn level_up(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
         let player = &mut objects[PLAYER];
         let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
         let fighter = player.fighter.as_mut().unwrap();
         if fighter.xp >= level_up_xp {
+            let new_spell = Spell::ALL
+                .iter()
+                .cloned()
+                .find(|spell| !game.spellbook.contains(spell));
+
+            let new_level = player.level + 1;
+            let available = |perk: Perk| {
+                !game.has_perk(perk)
+                    && perk
+                        .prerequisite()
+                        .is_none_or(|needed| game.has_perk(needed))
+                    && new_level >= perk.min_level()
+            };
+            let stats: Vec<LevelUp> = game
+                .class
+                .level_ups()
+                .iter()
+                .cloned()
+                .filter(|&choice| choice != LevelUp::ArcaneStudy || new_spell.is_some())
+                .collect();
+            let perks: Vec<LevelUp> = Perk::ALL
+                .iter()
+                .cloned()
+                .filter(|&perk| available(perk))
+                .map(LevelUp::Perk)
+                .collect();
+            let choices: Vec<LevelUp> = stats.iter().chain(perks.iter()).cloned().collect();
+
+            // every tree on one line, so the player sees what they have and what comes next
+            let mut header = String::from("Level up! Raise a stat or learn a perk.\n\n");
+            for tree in Perk::TREES.iter() {
+                let perks: Vec<String> = Perk::ALL
+                    .iter()
+                    .filter(|perk| perk.tree() == *tree)
+                    .map(|&perk| {
+                        let status = if game.has_perk(perk) {
+                            "learned".to_string()
+                        } else if available(perk) {
+                            "available".to_string()
+                        } else if new_level < perk.min_level() {
+                            format!("from level {}", perk.min_level())
+                        } else {
+                            format!("needs {}", perk.prerequisite().unwrap())
+                        };
+                        format!("{} ({})", perk, status)
+                    })
+                    .collect();
+                header.push_str(&format!("{}: {}\n", tree, perks.join(" > ")));
+            }
+
+            let options: Vec<String> = choices
+                .iter()
+                .map(|choice| match choice {
+                    LevelUp::Constitution => format!(
+                        "Constitution (+{}, from {}: HP and defense)",
+                        ATTRIBUTE_LEVEL_UP, fighter.attributes.constitution
+                    ),
+                    LevelUp::Strength => format!(
+                        "Strength (+{}, from {}: attack)",
+                        ATTRIBUTE_LEVEL_UP, fighter.attributes.strength
+                    ),
+                    LevelUp::Dexterity => format!(
+                        "Dexterity (+{}, from {}: accuracy, evasion, ranged attack)",
+                        ATTRIBUTE_LEVEL_UP, fighter.attributes.dexterity
+                    ),
+                    LevelUp::Precision => {
+                        format!("Precision (+1 to accuracy, from {})", fighter.base_accuracy)
+                    }
+                    LevelUp::Reflexes => {
+                        format!("Reflexes (+1 to evasion, from {})", fighter.base_evasion)
+                    }
+                    LevelUp::Intelligence => format!(
+                        "Intelligence (+{}, from {}: spellcasting)",
+                        ATTRIBUTE_LEVEL_UP, fighter.attributes.intelligence
+                    ),
+                    LevelUp::Willpower => format!(
+                        "Willpower (+{}, from {}: mana)",
+                        ATTRIBUTE_LEVEL_UP, fighter.attributes.willpower
+                    ),
+                    LevelUp::ArcaneStudy => {
+                        format!("Arcane study (learn {})", new_spell.unwrap())
+                    }
+                    LevelUp::Perk(perk) => format!("{}: {}", perk, perk.description()),
+                })
+                .collect();
+            let mut lines = vec![MenuLine::Category("Stats".into())];
+            lines.extend(options[..stats.len()].iter().cloned().map(MenuLine::Choice));
+            if !perks.is_empty() {
+                lines.push(MenuLine::Category("Perks".into()));
+                lines.extend(options[stats.len()..].iter().cloned().map(MenuLine::Choice));
+            }
+
             let mut choice = None;
             while choice.is_none() {
-                choice = menu(
-                    "Level up! Choose a stat to raise:\n",
-                    &[
-                        format!("Constitution (+20 to HP, from {})", fighter.base_max_hp),
-                        format!("Strength (+1 to attack, from {})", fighter.base_power),
-                        format!("Agility (+1 to defense, from {})", fighter.base_defense),
-                        format!("Arcane knowlage (+1 to spellcasting, from {})", fighter.base_spellcast_modifier),
-                        format!("Arcane power (+20 to mana, from {})", fighter.base_max_mana),
-                    ],
-                    LEVEL_SCREEN_WIDTH,
-                    tcod,
-                )
+                choice = grouped_menu(&header, &lines, LEVEL_SCREEN_WIDTH, tcod)
             }
 
             fighter.xp -= level_up_xp;
             player.level += 1;
-            match choice.unwrap() {
-                0 => {
-                    fighter.base_max_hp += 20;
-                    fighter.hp += 20;
+            match choices[choice.unwrap()] {
+                LevelUp::Constitution => {
+                    fighter.attributes.constitution += ATTRIBUTE_LEVEL_UP;
+                    fighter.hp += ATTRIBUTE_LEVEL_UP * HP_PER_CONSTITUTION;
+                }
+                LevelUp::Strength => {
+                    fighter.attributes.strength += ATTRIBUTE_LEVEL_UP;
+                }
+                LevelUp::Dexterity => {
+                    fighter.attributes.dexterity += ATTRIBUTE_LEVEL_UP;
                 }
-                1 => {
-                    fighter.base_power += 1;
+                LevelUp::Precision => {
+                    fighter.base_accuracy += 1;
                 }
-                2 => {
-                    fighter.base_defense += 1;
+                LevelUp::Reflexes => {
+                    fighter.base_evasion += 1;
                 }
-                3 => {
-                    fighter.base_spellcast_modifier += 1;
+                LevelUp::Intelligence => {
+                    fighter.attributes.intelligence += ATTRIBUTE_LEVEL_UP;
                 }
-                4 => {
-                    fighter.base_max_mana += 20;
-                    fighter.mana += 20;
+                LevelUp::Willpower => {
+                    fighter.attributes.willpower += ATTRIBUTE_LEVEL_UP;
+                    fighter.mana += ATTRIBUTE_LEVEL_UP * MANA_PER_WILLPOWER;
+                }
+                LevelUp::ArcaneStudy => {
+                    game.spellbook.push(new_spell.unwrap());
+                }
+                LevelUp::Perk(perk) => {
+                    game.messages
+                        .add(format!("You learned {}", perk), LIGHT_VIOLET);
+                    game.perks.push(perk);
                 }
-                _ => unreachable!(),
             }
         }
     }
@@ -1682,69 +6246,98 @@ impl Object {
         self.fighter.as_mut().unwrap().xp += xp;
     }
 
-    pub fn attack(&mut self, target: &mut Object, game: &mut Game, object: Option<usize>) {
-        let mut msg = String::new();
-
-        let damage = calculate_damage(
-            if let Some(id) = object {
-                if let Object {
-                    item: Some(item),
-                    name,
-                    ..
-                } = &game.inventory[id]
-                {
-                    match item {
-                        Item::Lightning { damage } => {
-                            msg = String::from("with lightning");
-                            *damage
-                        }
-                        Item::Fireball { damage, .. } => {
-                            msg = String::from("with fireball");
-                            *damage
-                        }
-                        Item::FireballStaff { .. } => {
-                            msg = String::from("with fireball staff");
-                            self.spellcast(game)
-                        }
-                        Item::Heal { .. }
-                        | Item::Confuse { .. }
-                        | Item::Shield
-                        | Item::Helmet
-                        | Item::Sword
-                        | Item::ManaPotion
-                        | Item::BodyArmor => {
-                            msg = format!("since {} is not a weapon", name);
-                            0
-                        }
-                    }
-                } else {
-                    0
+    pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
+        if self.name == "player" {
+            wear_equipment(game, |e| e.range == 0 && e.power_bonus > 0);
+        }
+        let power = self.power(game) as f32;
+        if self.strike(target, game, power, true, String::new()) {
+            let effects: Vec<OnHit> = self
+                .get_all_equipped(game)
+                .iter()
+                .filter(|e| e.range == 0 && e.slot != Slot::Ammunition)
+                .filter_map(|e| e.on_hit)
+                .collect();
+            for effect in effects {
+                apply_on_hit(effect, self, target, game);
+            }
+        }
+    }
+
+    pub fn strike(
+        &mut self,
+        target: &mut Object,
+        game: &mut Game,
+        base_damage: f32,
+        weapon: bool,
+        msg: String,
+    ) -> bool {
+        // spells always hit, only weapon attacks can miss or land a critical hit
+        let mut critical = false;
+        if weapon {
+            if target.name == "player"
+                && game.has_perk(Perk::Dodge)
+                && rand::thread_rng().gen::<f32>() < DODGE_CHANCE
+            {
+                game.messages
+                    .add(format!("You dodge the {}'s attack", self.name), LIGHT_GREEN);
+                if game.has_perk(Perk::Riposte) && self.distance_to(target) < 1.5 {
+                    target.attack(self, game);
                 }
-            } else {
-                self.power(game)
-            } as f32,
-            target.defense(game) as f32,
-        );
+                return false;
+            }
+            let hit = hit_chance(self.accuracy(game), target.evasion(game));
+            if rand::thread_rng().gen::<f32>() >= hit {
+                game.messages.add(
+                    format!("{} attacks {} but misses", self.name, target.name),
+                    LIGHT_GREY,
+                );
+                return false;
+            }
+            critical = rand::thread_rng().gen::<f32>() < critical_chance(self.critical(game));
+        }
+
+        let defense = if !weapon && self.name == "player" {
+            magic_defense(target, game)
+        } else {
+            target.defense(game) as f32
+        };
+        let mut damage = expected_damage(base_damage, defense);
+        if critical {
+            damage *= CRITICAL_MULTIPLIER;
+        }
+        let damage = round_randomly(damage);
+        let verb = if critical {
+            "critically hits"
+        } else {
+            "attacks"
+        };
 
         if damage > 0 {
             game.messages.add(
                 format!(
-                    "{} attacks {} for {} hp {}",
-                    self.name, target.name, damage, msg
+                    "{} {} {} for {} hp {}",
+                    self.name, verb, target.name, damage, msg
                 ),
                 LIGHT_RED,
             );
+            // armor only wears down from blows, not from hunger or poison
+            if target.name == "player" {
+                wear_equipment(game, |e| e.defense_bonus > 0);
+            }
             if let Some(xp) = target.take_damage(damage, game) {
                 self.get_xp(xp);
             }
+            true
         } else {
             game.messages.add(
                 format!(
-                    "{} attacks {} but it has no effect {}",
-                    self.name, target.name, msg
+                    "{} {} {} but it has no effect {}",
+                    self.name, verb, target.name, msg
                 ),
                 LIGHT_GREEN,
             );
+            false
         }
     }
 
@@ -1755,23 +6348,81 @@ impl Object {
         }
     }
 
-    pub fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut Vec<Object>) {
+    pub fn player_move_or_attack(
+        dx: i32,
+        dy: i32,
+        tcod: &mut Tcod,
+        game: &mut Game,
+        objects: &mut Vec<Object>,
+    ) -> PlayerAction {
         let x = objects[PLAYER].x + dx;
         let y = objects[PLAYER].y + dy;
 
+        // looking at the anvil without using it is free
+        let anvil_id = objects
+            .iter()
+            .position(|object| object.pos() == (x, y) && object.anvil.is_some());
+        if let Some(anvil_id) = anvil_id {
+            return if use_anvil(anvil_id, tcod, game, objects) {
+                PlayerAction::TookTurn
+            } else {
+                PlayerAction::DidntTakeTurn
+            };
+        }
+
+        let chest_id = objects.iter().position(|object| {
+            object.pos() == (x, y) && object.item.is_none() && object.container.is_some()
+        });
+        if let Some(chest_id) = chest_id {
+            open_chest(chest_id, tcod, game, objects);
+            return PlayerAction::TookTurn;
+        }
+
         let target_id = objects
             .iter()
             .position(|object| object.fighter.is_some() && object.pos() == (x, y));
 
+        // bumping into someone friendly starts a conversation instead of a fight
+        let npc_id = objects.iter().position(|object| {
+            object.pos() == (x, y)
+                && matches!(
+                    object.ai,
+                    Some(Ai::Npc { .. }) | Some(Ai::Shopkeeper { .. })
+                )
+        });
+        if let Some(npc_id) = npc_id {
+            talk(npc_id, tcod, game, objects);
+            return PlayerAction::TookTurn;
+        }
+
+        // companions step aside by swapping places
+        let follower_id = objects.iter().position(|object| {
+            object.pos() == (x, y) && matches!(object.ai, Some(Ai::Follower { .. }))
+        });
+        if let Some(follower_id) = follower_id {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            objects[follower_id].set_pos(player_x, player_y);
+            objects[PLAYER].set_pos(x, y);
+            return PlayerAction::TookTurn;
+        }
+
         match target_id {
             Some(target_id) => {
                 let (target, player) = mut_two(target_id, PLAYER, objects);
-                player.attack(target, game, None);
+                player.attack(target, game);
+                if objects[target_id].fighter.is_none() {
+                    killing_blow(game, objects);
+                }
             }
             None => {
+                let position = objects[PLAYER].pos();
                 Object::move_by(PLAYER, dx, dy, &game.map, objects);
+                if objects[PLAYER].pos() != position {
+                    describe_floor(game, objects);
+                }
             }
         }
+        PlayerAction::TookTurn
     }
 
     pub fn draw(&self, con: &mut dyn Console) {
@@ -1801,6 +6452,22 @@ impl Object {
     }
 }
 
+fn make_gold(x: i32, y: i32, amount: i32) -> Object {
+    let mut gold = Object::new(x, y, '$', "gold", GOLD, false);
+    gold.item = Some(Item::Gold);
+    gold.count = amount;
+    gold
+}
+
+// monsters sometimes carry a few coins, which they drop where they die
+fn drop_gold(monster: &Object, game: &mut Game) {
+    if rand::thread_rng().gen::<f32>() < MONSTER_GOLD_CHANCE {
+        let xp = monster.fighter.map_or(0, |f| f.xp);
+        let amount = rand::thread_rng().gen_range(1, xp / 5 + 2);
+        game.drops.push(make_gold(monster.x, monster.y, amount));
+    }
+}
+
 fn player_death(player: &mut Object, game: &mut Game) {
     game.messages.add("You died!", DARK_RED);
 
@@ -1808,7 +6475,30 @@ fn player_death(player: &mut Object, game: &mut Game) {
     player.color = DARK_RED;
 }
 
+// what happens on any monster's death, whatever its kind
+fn monster_killed(monster: &Object, game: &mut Game) {
+    if let Some(ingredient) = ingredient_of(&monster.name) {
+        if rand::thread_rng().gen::<f32>() < INGREDIENT_CHANCE {
+            let mut ingredient = make_item(ingredient, monster.x, monster.y, game.dungeon_level);
+            ingredient.blessing = Blessing::Uncursed;
+            game.drops.push(ingredient);
+        }
+    }
+    for quest in game.quests.iter_mut() {
+        if let Objective::Kill {
+            monster: ref target,
+            ..
+        } = quest.objective
+        {
+            if quest.status == QuestStatus::Active && *target == monster.name {
+                quest.status = QuestStatus::ObjectiveDone;
+            }
+        }
+    }
+}
+
 fn archer_death(archer: &mut Object, game: &mut Game) {
+    monster_killed(archer, game);
     game.messages.add(
         format!(
             "{} is dead! You gain {} experience points",
@@ -1817,15 +6507,18 @@ fn archer_death(archer: &mut Object, game: &mut Game) {
         ),
         DARK_GREEN,
     );
+    drop_gold(archer, game);
     archer.char = '%';
     archer.color = DARK_RED;
     archer.blocks = false;
     archer.fighter = None;
     archer.ai = None;
+    archer.item = Some(corpse_of(&archer.name));
     archer.name = format!("remains of {}", archer.name);
 }
 
 fn monster_death(monster: &mut Object, game: &mut Game) {
+    monster_killed(monster, game);
     game.messages.add(
         format!(
             "{} is dead! You gain {} experience points",
@@ -1834,21 +6527,28 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
         ),
         DARK_GREEN,
     );
+    drop_gold(monster, game);
     monster.char = '%';
     monster.color = DARK_RED;
     monster.blocks = false;
     monster.fighter = None;
     monster.ai = None;
+    monster.item = Some(corpse_of(&monster.name));
     monster.name = format!("remains of {}", monster.name);
 }
 
-fn get_names_under_mouse(mouse: Mouse, objects: &Vec<Object>, fov_map: &FovMap) -> String {
+fn get_names_under_mouse(
+    mouse: Mouse,
+    objects: &Vec<Object>,
+    fov_map: &FovMap,
+    game: &Game,
+) -> String {
     let (x, y) = (mouse.cx as i32, mouse.cy as i32);
 
     let names = objects
         .iter()
         .filter(|obj| obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y))
-        .map(|obj| obj.name.clone())
+        .map(|obj| obj.display_name(game))
         .collect::<Vec<_>>();
 
     names.join(", ")
@@ -1866,6 +6566,37 @@ fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mu
     Object::move_by(id, dx, dy, map, objects);
 }
 
+// perks that trigger when the player kills a monster in melee
+fn killing_blow(game: &mut Game, objects: &mut [Object]) {
+    if game.has_perk(Perk::Bloodlust) {
+        game.messages
+            .add("The kill fills you with new vigor", LIGHT_RED);
+        objects[PLAYER].heal(BLOODLUST_HEAL, game);
+    }
+    if game.has_perk(Perk::Cleave) {
+        let next = objects.iter().enumerate().position(|(id, o)| {
+            id != PLAYER
+                && o.fighter.is_some()
+                && o.distance_to(&objects[PLAYER]) < 1.5
+                && matches!(
+                    o.ai,
+                    Some(Ai::Basic)
+                        | Some(Ai::Archer { .. })
+                        | Some(Ai::Confused { .. })
+                        | Some(Ai::Slowed { .. })
+                )
+        });
+        if let Some(next) = next {
+            game.messages.add(
+                format!("You cleave on into the {}", objects[next].name),
+                LIGHT_RED,
+            );
+            let (target, player) = mut_two(next, PLAYER, objects);
+            player.attack(target, game);
+        }
+    }
+}
+
 fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
     assert!(first_index != second_index);
     let split_at_index = cmp::max(first_index, second_index);
@@ -1885,12 +6616,20 @@ fn ai_basic(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut Vec<O
             move_towards(monster_id, player_x, player_y, &game.map, objects);
         } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
             let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(player, game, None);
+            monster.attack(player, game);
         }
     }
     Ai::Basic
 }
 
+fn ai_follower(monster_id: usize, game: &Game, objects: &mut Vec<Object>, dialogue: String) -> Ai {
+    if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+        let (player_x, player_y) = objects[PLAYER].pos();
+        move_towards(monster_id, player_x, player_y, &game.map, objects);
+    }
+    Ai::Follower { dialogue }
+}
+
 fn ai_confused(
     monster_id: usize,
     _tcod: &Tcod,
@@ -1921,6 +6660,36 @@ fn ai_confused(
     }
 }
 
+fn ai_slowed(
+    monster_id: usize,
+    tcod: &Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+    previous_ai: Box<Ai>,
+    num_turns: i32,
+) -> Ai {
+    let previous_ai = if num_turns % 2 == 0 {
+        objects[monster_id].ai = Some(*previous_ai);
+        ai_take_turn(monster_id, tcod, game, objects);
+        Box::new(objects[monster_id].ai.take().unwrap_or(Ai::Basic))
+    } else {
+        previous_ai
+    };
+
+    if num_turns <= 1 {
+        game.messages.add(
+            format!("the {} is no longer slowed", objects[monster_id].name),
+            RED,
+        );
+        *previous_ai
+    } else {
+        Ai::Slowed {
+            previous_ai,
+            num_turns: num_turns - 1,
+        }
+    }
+}
+
 fn ai_archer(
     monster_id: usize,
     tcod: &Tcod,
@@ -1935,7 +6704,7 @@ fn ai_archer(
             move_towards(monster_id, player_x, player_y, &game.map, objects);
         } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
             let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(player, game, None);
+            monster.attack(player, game);
         }
     }
     Ai::Archer { range }
@@ -1944,15 +6713,25 @@ fn ai_archer(
 fn ai_take_turn(monster_id: usize, tcod: &Tcod, game: &mut Game, objects: &mut Vec<Object>) {
     use Ai::*;
     if let Some(ai) = objects[monster_id].ai.take() {
+        let fighter = objects[monster_id].fighter.is_some();
         let new_ai = match ai {
             Basic => ai_basic(monster_id, tcod, game, objects),
             Archer { range } => ai_archer(monster_id, tcod, game, objects, range),
+            Shopkeeper { .. } | Npc { .. } => ai,
+            Follower { dialogue } => ai_follower(monster_id, game, objects, dialogue),
             Confused {
                 previous_ai,
                 num_turns,
             } => ai_confused(monster_id, tcod, game, objects, previous_ai, num_turns),
+            Slowed {
+                previous_ai,
+                num_turns,
+            } => ai_slowed(monster_id, tcod, game, objects, previous_ai, num_turns),
         };
-        objects[monster_id].ai = Some(new_ai);
+        // a monster killed during its own turn, say by a riposte, stays a corpse
+        if !fighter || objects[monster_id].fighter.is_some() {
+            objects[monster_id].ai = Some(new_ai);
+        }
     }
 }
 
@@ -1990,32 +6769,58 @@ fn handle_keys(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> P
             let level = player.level;
             let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
             if let Some(fighter) = player.fighter.as_ref() {
+                let attributes: Vec<String> = Attribute::ALL
+                    .iter()
+                    .map(|&attribute| {
+                        format!("{}: {}", attribute, fighter.attributes.get(attribute))
+                    })
+                    .collect();
+                let attributes = attributes.join("\n");
                 let msg = format!(
                     "Character information
 
+Class: {}
 Level: {}
 Experience: {}
 Experience to level up: {}
 
+{}
+
 Maximum HP: {}
 Attack: {}
 Maximum mana: {}
 Spellcasting: {}
-Defense: {}",
+Defense: {}
+Accuracy: {}
+Evasion: {}
+Critical chance: {:.0}%",
+                    game.class,
                     level,
                     fighter.xp,
                     level_up_xp,
+                    attributes,
                     player.max_hp(game),
                     player.power(game),
                     player.max_mana(game),
-                    player.spellcast_only(game),
-                    player.defense(game)
+                    player.spellcast(game),
+                    player.defense(game),
+                    player.accuracy(game),
+                    player.evasion(game),
+                    critical_chance(player.critical(game)) * 100.0
                 );
                 msgbox(&msg, CHARACTER_SCREEN_WIDTH, tcod);
             }
 
             DidntTakeTurn
         }
+        (Key { code: Text, .. }, "q", true) => {
+            quest_log(game, tcod);
+            DidntTakeTurn
+        }
+        (Key { code: Text, .. }, "e", true) => {
+            equipment_screen(game, tcod);
+            DidntTakeTurn
+        }
         (Key { code: Text, .. }, "<", true) => {
             let player_on_stairs = objects
                 .iter()
@@ -2052,24 +6857,38 @@ Defense: {}",
             }
         }
         (Key { code: Text, .. }, "d", true) => {
-            let inventory_index = inventory_menu(&game.inventory, "Select an item to drop\n", tcod);
+            let inventory_index = inventory_menu(game, "Select an item to drop\n", tcod);
             if let Some(inventory_index) = inventory_index {
-                drop_item(inventory_index, game, objects);
+                let count = game.inventory[inventory_index].count;
+                if let Some(amount) = choose_quantity(count, "Drop", tcod) {
+                    drop_item(inventory_index, amount, game, objects);
+                }
             }
             DidntTakeTurn
         }
-        (Key { code: Text, .. }, "g", true) => {
-            let item_id = objects
-                .iter()
-                .position(|object| object.pos() == objects[PLAYER].pos() && object.item.is_some());
-            if let Some(item_id) = item_id {
-                pick_item_up(item_id, game, objects);
+        (Key { code: Text, .. }, "t", true) => {
+            let inventory_index = inventory_menu(
+                game,
+                "Press the key next to an item to throw it, or any other to cancel.\n",
+                tcod,
+            );
+            if let Some(inventory_index) = inventory_index {
+                throw_item(inventory_index, tcod, game, objects)
+            } else {
+                DidntTakeTurn
             }
+        }
+        (Key { code: Text, .. }, "f", true) => fire_weapon(tcod, game, objects),
+        (Key { code: Text, .. }, "m", true) => spell_menu(tcod, game, objects),
+        (Key { code: Text, .. }, "z", true) => zap_menu(tcod, game, objects),
+        (Key { code: Text, .. }, "r", true) => crafting_menu(tcod, game, objects),
+        (Key { code: Text, .. }, "g", true) => {
+            pick_up_menu(tcod, game, objects);
             DidntTakeTurn
         }
         (Key { code: Text, .. }, "i", true) => {
             let inventory_index = inventory_menu(
-                &game.inventory,
+                game,
                 "Press the key next to an item to use itm or any other to cancel.\n",
                 tcod,
             );
@@ -2080,58 +6899,132 @@ Defense: {}",
             }
         }
         (Key { code: Text, .. }, "k", true) => {
-            Object::player_move_or_attack(0, -1, game, objects);
-            TookTurn
+            Object::player_move_or_attack(0, -1, tcod, game, objects)
         }
         (Key { code: Text, .. }, "j", true) => {
-            Object::player_move_or_attack(0, 1, game, objects);
-            TookTurn
+            Object::player_move_or_attack(0, 1, tcod, game, objects)
         }
         (Key { code: Text, .. }, "h", true) => {
-            Object::player_move_or_attack(-1, 0, game, objects);
-            TookTurn
+            Object::player_move_or_attack(-1, 0, tcod, game, objects)
         }
         (Key { code: Text, .. }, "l", true) => {
-            Object::player_move_or_attack(1, 0, game, objects);
-            TookTurn
+            Object::player_move_or_attack(1, 0, tcod, game, objects)
         }
         (Key { code: Text, .. }, "s", true) => {
-            game.messages.add("You chose to sleep thos round", YELLOW);
-            TookTurn
+            // every turn spent resting has already been played out
+            rest(tcod, game, objects);
+            DidntTakeTurn
         }
         (Key { code: Escape, .. }, _, _) => Exit,
         _ => DidntTakeTurn,
     }
 }
 
+// shows what is worn where, laid out roughly like the body wearing it
+fn equipment_screen(game: &Game, tcod: &mut Tcod) {
+    let width = EQUIPMENT_SCREEN_WIDTH;
+    let height = EQUIPMENT_SCREEN_HEIGHT;
+    let column = width / 3;
+    let (left, center, right) = (column / 2, width / 2, width - column / 2);
+    // the player faces the screen, so their right hand is on the left
+    let layout = [
+        (Slot::Head, center, 3),
+        (Slot::RightHand, left, 7),
+        (Slot::Neck, center, 7),
+        (Slot::LeftHand, right, 7),
+        (Slot::Hands, left, 11),
+        (Slot::Body, center, 11),
+        (Slot::Back, right, 11),
+        (Slot::RightRing, left, 15),
+        (Slot::Feet, center, 15),
+        (Slot::LeftRing, right, 15),
+        (Slot::Ammunition, center, 19),
+    ];
+
+    let mut window = Offscreen::new(width, height);
+    window.set_default_foreground(WHITE);
+    window.print_ex(
+        center,
+        1,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        "Equipment",
+    );
+
+    for &(slot, x, y) in &layout {
+        let worn = get_equipped_in_slot(slot, &game.inventory).map(|id| &game.inventory[id]);
+        let name = match worn {
+            Some(item) if slot == Slot::LeftHand && item.equipment.unwrap().slot != slot => {
+                format!("({})", item.display_name(game))
+            }
+            Some(item) if item.count > 1 => {
+                format!("{} ({})", item.display_name(game), item.count)
+            }
+            Some(item) => item.display_name(game),
+            None => "-".into(),
+        };
+        let name: String = name.chars().take(column as usize - 1).collect();
+
+        window.set_default_foreground(LIGHT_GREY);
+        window.print_ex(
+            x,
+            y,
+            BackgroundFlag::None,
+            TextAlignment::Center,
+            slot.to_string(),
+        );
+        window.set_default_foreground(if worn.is_some() { WHITE } else { DARK_GREY });
+        window.print_ex(x, y + 1, BackgroundFlag::None, TextAlignment::Center, name);
+
+        if let Some(equipment) = worn.and_then(|item| item.equipment) {
+            if equipment.slot != Slot::Ammunition {
+                window.set_default_foreground(
+                    if equipment.durability * 2 < equipment.max_durability {
+                        ORANGE
+                    } else {
+                        LIGHT_GREY
+                    },
+                );
+                window.print_ex(
+                    x,
+                    y + 2,
+                    BackgroundFlag::None,
+                    TextAlignment::Center,
+                    format!(
+                        "{} {}/{}",
+                        equipment.condition(),
+                        equipment.durability,
+                        equipment.max_durability
+                    ),
+                );
+            }
+        }
+    }
+
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = SCREEN_HEIGHT / 2 - height / 2;
+    blit(
+        &window,
+        (0, 0),
+        (width, height),
+        &mut tcod.root,
+        (x, y),
+        1.0,
+        0.7,
+    );
+    tcod.root.flush();
+    tcod.root.wait_for_keypress(true);
+}
+
 fn msgbox(text: &str, width: i32, tcod: &mut Tcod) {
     let options: &[&str] = &[];
     menu(text, options, width, tcod);
 }
 
-fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
+fn new_game(class: Class, tcod: &mut Tcod) -> (Game, Vec<Object>) {
     let mut player = Object::new(0, 0, '@', "player", WHITE, true);
     player.alive = true;
-    player.fighter = Some(Fighter {
-        base_max_hp: 100,
-        hp: 100,
-        base_defense: 1,
-        base_power: 2,
-        xp: 0,
-        base_max_mana: 20,
-        base_spellcast_modifier: 3,
-        mana: 20,
-        on_death: DeathCallback::Player,
-    });
-
-    //let npc = Object::new(
-    //SCREEN_WIDTH / 2 - 5,
-    //SCREEN_HEIGHT / 2,
-    //'@',
-    //"NPC",
-    //YELLOW,
-    //false,
-    //);
+    player.fighter = Some(class.fighter());
 
     let mut objects = vec![player];
 
@@ -2139,22 +7032,52 @@ fn new_game(tcod: &mut Tcod) -> (Game, Vec<Object>) {
         map: make_map(&mut objects, 0),
         messages: Messages::new(),
         inventory: vec![],
+        spellbook: class.spells(),
+        nutrition: START_NUTRITION,
+        appearances: random_appearances(),
+        identified: vec![],
         dungeon_level: 0,
+        class,
+        perks: vec![],
+        gold: 0,
+        detect_monsters: 0,
+        flags: vec![],
+        quests: vec![],
+        drops: vec![],
     };
 
-    let mut dagger = Object::new(0, 0, '-', "dagger", SKY, false);
-    dagger.item = Some(Item::Sword);
-    dagger.equipment = Some(Equipment {
-        equipped: true,
-        slot: Slot::LeftHand,
-        max_hp_bonus: 0,
-        defense_bonus: 0,
-        power_bonus: 2,
-        mana_usage: 0,
-        max_mana_bonus: 0,
-        spellcast_bonus: 0,
-    });
-    game.inventory.push(dagger);
+    // an old hermit waits next to the player, in the first room
+    let (x, y) = objects[PLAYER].pos();
+    if !is_blocked(x + 2, y, &game.map, &objects) {
+        let mut hermit = Object::new(x + 2, y, '@', "old hermit", LIGHT_AZURE, true);
+        hermit.ai = Some(Ai::Npc {
+            dialogue: "hermit".into(),
+        });
+        objects.push(hermit);
+    }
+    quest_events(&mut game, &mut objects);
+
+    // the starting kit is known and ready to use
+    for mut item in class.starting_items() {
+        if let Some(ref mut equipment) = item.equipment {
+            equipment.equipped = true;
+            equipment.identified = true;
+        }
+        if !game.is_identified(&item.name) {
+            game.identified.push(item.name.clone());
+        }
+        game.inventory.push(item);
+    }
+
+    // the class only gives the base values, attributes and kit add to them
+    let (max_hp, max_mana) = (
+        objects[PLAYER].max_hp(&game),
+        objects[PLAYER].max_mana(&game),
+    );
+    if let Some(ref mut fighter) = objects[PLAYER].fighter {
+        fighter.hp = max_hp;
+        fighter.mana = max_mana;
+    }
 
     initialize_fov(tcod, &game.map);
 
@@ -2201,19 +7124,93 @@ fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         previous_player_pos = objects[PLAYER].pos();
 
         let player_action = handle_keys(tcod, game, objects);
+        objects.append(&mut game.drops);
+        remove_broken_equipment(game);
+        update_quests(game, objects);
         if player_action == PlayerAction::Exit {
             save_game(game, objects).unwrap();
             break;
         }
 
         if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, tcod, game, objects);
-                }
-            }
+            take_world_turn(tcod, game, objects);
+            remove_broken_equipment(game);
+        }
+    }
+}
+
+fn take_world_turn(tcod: &Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    for id in 0..objects.len() {
+        if objects[id].ai.is_some() {
+            ai_take_turn(id, tcod, game, objects);
+        }
+    }
+
+    if game.detect_monsters > 0 {
+        game.detect_monsters -= 1;
+    }
+
+    let hunger = Hunger::from_nutrition(game.nutrition);
+    game.nutrition -= 1;
+    let new_hunger = Hunger::from_nutrition(game.nutrition);
+    if new_hunger != hunger {
+        match new_hunger {
+            Hunger::Hungry => game.messages.add("You are getting hungry", YELLOW),
+            Hunger::Weak => game.messages.add("You feel weak from hunger", ORANGE),
+            Hunger::Starving => game.messages.add("You are starving!", RED),
+            _ => {}
+        }
+    }
+
+    if new_hunger == Hunger::Starving && game.nutrition % STARVATION_INTERVAL == 0 {
+        objects[PLAYER].take_damage(STARVATION_DAMAGE, game);
+    }
+
+    if new_hunger.penalty() == 0 && visible_targets(tcod, objects, None).is_empty() {
+        objects[PLAYER].regenerate(game);
+    }
+
+    // loot from monsters killed during the turn
+    objects.append(&mut game.drops);
+}
+
+// the player rests until fully healed, or until an enemy shows up or hurts the player
+fn rest(tcod: &Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    if !visible_targets(tcod, objects, None).is_empty() {
+        game.messages
+            .add("You cannot rest with enemies in view", YELLOW);
+        return;
+    }
+
+    for turn in 0..REST_MAX_TURNS {
+        let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+        let mana = objects[PLAYER].fighter.map_or(0, |f| f.mana);
+        let hunger = Hunger::from_nutrition(game.nutrition);
+        if hp == objects[PLAYER].max_hp(game) && mana == objects[PLAYER].max_mana(game) {
+            game.messages
+                .add(format!("You rested for {} turns", turn), YELLOW);
+            return;
+        }
+
+        take_world_turn(tcod, game, objects);
+
+        let hurt = objects[PLAYER].fighter.map_or(0, |f| f.hp) < hp;
+        let hungrier = Hunger::from_nutrition(game.nutrition) != hunger;
+        if hurt
+            || hungrier
+            || !objects[PLAYER].alive
+            || !visible_targets(tcod, objects, None).is_empty()
+        {
+            game.messages.add(
+                format!("You were interrupted after resting {} turns", turn + 1),
+                LIGHT_RED,
+            );
+            return;
         }
     }
+
+    game.messages
+        .add(format!("You rested for {} turns", REST_MAX_TURNS), YELLOW);
 }
 
 fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
@@ -2228,11 +7225,56 @@ fn next_level(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
         "After a rare moment of peace, you decent deeper into the heart of the dungeon...",
         YELLOW,
     );
+    // companions close by follow the player down the stairs, the others are lost
+    let followers: Vec<Object> = objects
+        .iter()
+        .filter(|object| {
+            matches!(object.ai, Some(Ai::Follower { .. }))
+                && object.distance_to(&objects[PLAYER]) < 3.0
+        })
+        .cloned()
+        .collect();
+    for quest in game.quests.iter_mut() {
+        if let Objective::Escort { ref npc, .. } = quest.objective {
+            let follows = followers.iter().any(|follower| follower.name == *npc);
+            if quest.status == QuestStatus::Active && !follows {
+                quest.status = QuestStatus::Failed;
+                game.messages.add(
+                    format!("You left the {} behind. Quest failed: {}", npc, quest.name),
+                    RED,
+                );
+            }
+        }
+    }
+
     game.dungeon_level += 1;
     game.map = make_map(objects, game.dungeon_level);
+
+    let (x, y) = objects[PLAYER].pos();
+    for mut follower in followers {
+        let spot = (-1..=1)
+            .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+            .find(|&(x, y)| !is_blocked(x, y, &game.map, objects));
+        if let Some((x, y)) = spot {
+            follower.set_pos(x, y);
+            objects.push(follower);
+        }
+    }
+    quest_events(game, objects);
+
     initialize_fov(tcod, &game.map);
 }
 
+fn choose_class(img: &tcod::image::Image, tcod: &mut Tcod) -> Option<Class> {
+    tcod::image::blit_2x(img, (0, 0), (-1, -1), &mut tcod.root, (0, 0));
+
+    let options: Vec<String> = Class::ALL
+        .iter()
+        .map(|class| format!("{}: {}", class, class.description()))
+        .collect();
+    menu("Choose your class\n", &options, CLASS_SCREEN_WIDTH, tcod).map(|choice| Class::ALL[choice])
+}
+
 fn main_menu(tcod: &mut Tcod) {
     let img = tcod::image::Image::from_file("images/menu_background.png")
         .ok()
@@ -2263,8 +7305,10 @@ fn main_menu(tcod: &mut Tcod) {
         match choice {
             Some(0) => {
                 // new game
-                let (mut game, mut objects) = new_game(tcod);
-                play_game(tcod, &mut game, &mut objects);
+                if let Some(class) = choose_class(&img, tcod) {
+                    let (mut game, mut objects) = new_game(class, tcod);
+                    play_game(tcod, &mut game, &mut objects);
+                }
             }
             Some(1) => {
                 match load_game() {
@@ -2286,6 +7330,703 @@ fn main_menu(tcod: &mut Tcod) {
     }
 }
 
+// a conversation tree, loaded from dialogue/<name>.json
+#[derive(Deserialize)]
+struct Dialogue {
+    start: String,
+    nodes: HashMap<String, DialogueNode>,
+}
+
+#[derive(Deserialize)]
+struct DialogueNode {
+    text: String,
+    choices: Vec<DialogueChoice>,
+}
+
+#[derive(Deserialize)]
+struct DialogueChoice {
+    text: String,
+    // the conversation ends after a choice without a next node
+    #[serde(default)]
+    next: Option<String>,
+    #[serde(default)]
+    actions: Vec<DialogueAction>,
+    // only offered when the flag is set, or when it isn't
+    #[serde(default)]
+    requires: Option<String>,
+    #[serde(default)]
+    unless: Option<String>,
+}
+
+#[derive(Deserialize)]
+enum DialogueAction {
+    GiveItem(Item),
+    GiveGold(i32),
+    SetFlag(String),
+    StartQuest(String),
+    OpenShop,
+}
+
+fn load_dialogue(name: &str) -> Result<Dialogue, Box<dyn Error>> {
+    let mut json = String::new();
+    let mut file = File::open(format!("dialogue/{}.json", name))?;
+    file.read_to_string(&mut json)?;
+    let dialogue = serde_json::from_str::<Dialogue>(&json)?;
+    Ok(dialogue)
+}
+
+fn talk(npc_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let topic = match objects[npc_id].ai {
+        Some(Ai::Npc { ref dialogue }) => dialogue.clone(),
+        Some(Ai::Shopkeeper { .. }) => "shopkeeper".into(),
+        _ => return,
+    };
+    let dialogue = match load_dialogue(&topic) {
+        Ok(dialogue) => dialogue,
+        Err(_) => {
+            game.messages.add(
+                format!("The {} has nothing to say", objects[npc_id].name),
+                LIGHT_GREY,
+            );
+            return;
+        }
+    };
+
+    let mut node_id = dialogue.start.clone();
+    while let Some(node) = dialogue.nodes.get(&node_id) {
+        let choices: Vec<&DialogueChoice> = node
+            .choices
+            .iter()
+            .filter(|choice| {
+                choice
+                    .requires
+                    .as_ref()
+                    .is_none_or(|flag| game.flags.contains(flag))
+                    && choice
+                        .unless
+                        .as_ref()
+                        .is_none_or(|flag| !game.flags.contains(flag))
+            })
+            .collect();
+        let options: Vec<&str> = choices.iter().map(|choice| choice.text.as_str()).collect();
+        let header = format!("The {} says:\n\n\"{}\"\n", objects[npc_id].name, node.text);
+
+        let choice = match menu(&header, &options, DIALOGUE_WIDTH, tcod) {
+            Some(choice) => choices[choice],
+            None => return,
+        };
+        for action in &choice.actions {
+            dialogue_action(action, npc_id, tcod, game, objects);
+        }
+        match choice.next {
+            Some(ref next) => node_id = next.clone(),
+            None => return,
+        }
+    }
+}
+
+fn dialogue_action(
+    action: &DialogueAction,
+    npc_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    match *action {
+        DialogueAction::GiveItem(kind) => {
+            let (x, y) = objects[PLAYER].pos();
+            let item = make_item(kind, x, y, game.dungeon_level);
+            game.messages.add(
+                format!(
+                    "The {} gives you {}",
+                    objects[npc_id].name,
+                    item.display_name(game)
+                ),
+                LIGHT_CYAN,
+            );
+            // whatever doesn't fit lands at the player's feet
+            if let Some(item) = add_to_inventory(item, &objects[PLAYER].attributes(), game) {
+                objects.push(item);
+            }
+        }
+        DialogueAction::GiveGold(amount) => {
+            game.messages.add(
+                format!("The {} gives you {} gold", objects[npc_id].name, amount),
+                GOLD,
+            );
+            game.gold += amount;
+        }
+        DialogueAction::SetFlag(ref flag) => {
+            if !game.flags.contains(flag) {
+                game.flags.push(flag.clone());
+            }
+        }
+        DialogueAction::StartQuest(ref id) => {
+            let quest = match load_quest(id) {
+                Ok(quest) => quest,
+                Err(_) => return,
+            };
+            // the one to be escorted starts following the player right away
+            if let Objective::Escort {
+                ref npc,
+                ref dialogue,
+                ..
+            } = quest.objective
+            {
+                if objects[npc_id].name == *npc {
+                    objects[npc_id].ai = Some(Ai::Follower {
+                        dialogue: dialogue.clone(),
+                    });
+                }
+            }
+            start_quest(quest, game, objects);
+        }
+        DialogueAction::OpenShop => match objects[npc_id].ai {
+            Some(Ai::Shopkeeper { .. }) => shop_menu(npc_id, tcod, game, objects),
+            _ => game.messages.add(
+                format!("The {} has nothing to sell", objects[npc_id].name),
+                LIGHT_GREY,
+            ),
+        },
+    }
+}
+
+// a quest as loaded from quests/<id>.json, kept in the save with its progress
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Quest {
+    #[serde(default)]
+    id: String,
+    name: String,
+    description: String,
+    objective: Objective,
+    #[serde(default)]
+    reward: Reward,
+    // quests with a trigger start by themselves when the player reaches that level
+    #[serde(default)]
+    trigger: Option<u32>,
+    #[serde(default)]
+    status: QuestStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Objective {
+    Kill {
+        monster: String,
+        kind: String,
+        level: u32,
+    },
+    Fetch {
+        item: String,
+        level: u32,
+    },
+    Escort {
+        npc: String,
+        dialogue: String,
+        from: u32,
+        to: u32,
+    },
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Reward {
+    #[serde(default)]
+    xp: i32,
+    #[serde(default)]
+    gold: i32,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum QuestStatus {
+    #[default]
+    Active,
+    ObjectiveDone,
+    Completed,
+    Failed,
+}
+
+impl std::fmt::Display for Objective {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Objective::Kill {
+                ref monster, level, ..
+            } => write!(f, "Kill {} on level {}", monster, level),
+            Objective::Fetch { ref item, level } => {
+                write!(f, "Find the {} on level {}", item, level)
+            }
+            Objective::Escort { ref npc, to, .. } => {
+                write!(f, "Bring the {} to level {}", npc, to)
+            }
+        }
+    }
+}
+
+fn load_quest(id: &str) -> Result<Quest, Box<dyn Error>> {
+    let mut json = String::new();
+    let mut file = File::open(format!("quests/{}.json", id))?;
+    file.read_to_string(&mut json)?;
+    let mut quest = serde_json::from_str::<Quest>(&json)?;
+    quest.id = id.into();
+    Ok(quest)
+}
+
+fn quest_ids() -> Vec<String> {
+    let mut ids: Vec<String> = std::fs::read_dir("quests")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    match path.extension() {
+                        Some(extension) if extension == "json" => path
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().into_owned()),
+                        _ => None,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+fn start_quest(mut quest: Quest, game: &mut Game, objects: &mut Vec<Object>) {
+    if game.quests.iter().any(|started| started.id == quest.id) {
+        return;
+    }
+    game.messages
+        .add(format!("New quest: {}", quest.name), LIGHT_CYAN);
+
+    // the player won't arrive on this level again, so its target is put here right away,
+    // and a target on a level that was already left is moved here as well
+    let level = game.dungeon_level;
+    match quest.objective {
+        Objective::Kill {
+            level: ref mut quest_level,
+            ..
+        }
+        | Objective::Fetch {
+            level: ref mut quest_level,
+            ..
+        } if *quest_level <= level => {
+            *quest_level = level;
+            place_quest_target(&quest.objective, game, objects);
+        }
+        _ => {}
+    }
+    game.quests.push(quest);
+}
+
+fn random_free_tile(game: &Game, objects: &[Object]) -> Option<(i32, i32)> {
+    for _ in 0..1000 {
+        let x = rand::thread_rng().gen_range(1, MAP_WIDTH - 1);
+        let y = rand::thread_rng().gen_range(1, MAP_HEIGHT - 1);
+        if !game.map[x as usize][y as usize].blocked
+            && !objects.iter().any(|object| object.pos() == (x, y))
+        {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+// starts the quests triggered by reaching the current level and puts quest targets in the dungeon
+fn quest_events(game: &mut Game, objects: &mut Vec<Object>) {
+    let level = game.dungeon_level;
+
+    // quests started below place their targets themselves
+    let objectives: Vec<Objective> = game
+        .quests
+        .iter()
+        .filter(|quest| quest.status == QuestStatus::Active)
+        .map(|quest| quest.objective.clone())
+        .collect();
+    for objective in objectives {
+        place_quest_target(&objective, game, objects);
+    }
+
+    for id in quest_ids() {
+        if game.quests.iter().any(|quest| quest.id == id) {
+            continue;
+        }
+        let quest = match load_quest(&id) {
+            Ok(quest) => quest,
+            Err(_) => continue,
+        };
+
+        if quest.trigger == Some(level) {
+            game.messages.add(quest.description.clone(), LIGHT_CYAN);
+            start_quest(quest, game, objects);
+        } else if let Objective::Escort {
+            ref npc,
+            ref dialogue,
+            from,
+            ..
+        } = quest.objective
+        {
+            if from == level {
+                if let Some((x, y)) = random_free_tile(game, objects) {
+                    let mut captive = Object::new(x, y, '@', npc, LIGHT_CYAN, true);
+                    captive.ai = Some(Ai::Npc {
+                        dialogue: dialogue.clone(),
+                    });
+                    objects.push(captive);
+                }
+            }
+        }
+    }
+}
+
+// puts the monster or item the quest is after on the current level, if it belongs there
+fn place_quest_target(objective: &Objective, game: &Game, objects: &mut Vec<Object>) {
+    let level = game.dungeon_level;
+    let (x, y) = match random_free_tile(game, objects) {
+        Some(tile) => tile,
+        None => return,
+    };
+    match *objective {
+        Objective::Kill {
+            ref monster,
+            ref kind,
+            level: quest_level,
+        } if quest_level == level && MONSTER_KINDS.contains(&kind.as_str()) => {
+            // a unique is a tougher version of an ordinary monster
+            let mut unique = make_monster(kind, x, y);
+            unique.name = monster.clone();
+            if let Some(ref mut fighter) = unique.fighter {
+                fighter.base_max_hp *= 2;
+                fighter.hp = fighter.base_max_hp;
+                fighter.base_power += 2;
+                fighter.xp *= 3;
+            }
+            objects.push(unique);
+        }
+        Objective::Fetch {
+            ref item,
+            level: quest_level,
+        } if quest_level == level => {
+            let mut relic = make_item(Item::Relic, x, y, level);
+            relic.name = item.clone();
+            relic.blessing = Blessing::Uncursed;
+            objects.push(relic);
+        }
+        _ => {}
+    }
+}
+
+// removes a quest item from the inventory or one of the bags in it
+fn take_relic(name: &str, game: &mut Game) -> bool {
+    let is_relic = |item: &Object| item.item == Some(Item::Relic) && item.name == name;
+    if let Some(id) = game.inventory.iter().position(is_relic) {
+        game.inventory.remove(id);
+        return true;
+    }
+    for bag in game.inventory.iter_mut() {
+        if let Some(ref mut container) = bag.container {
+            if let Some(id) = container.contents.iter().position(is_relic) {
+                container.contents.remove(id);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// checks the objectives of the active quests and pays out the ones that are done
+fn update_quests(game: &mut Game, objects: &mut Vec<Object>) {
+    for index in 0..game.quests.len() {
+        if game.quests[index].status == QuestStatus::Active {
+            let done = match game.quests[index].objective.clone() {
+                Objective::Kill { .. } => false,
+                Objective::Fetch { item, .. } => take_relic(&item, game),
+                Objective::Escort {
+                    npc, dialogue, to, ..
+                } => {
+                    let escort = objects.iter().position(|object| {
+                        object.name == npc && matches!(object.ai, Some(Ai::Follower { .. }))
+                    });
+                    match escort {
+                        Some(escort) if game.dungeon_level >= to => {
+                            objects[escort].ai = Some(Ai::Npc { dialogue });
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+            };
+            if done {
+                game.quests[index].status = QuestStatus::ObjectiveDone;
+            }
+        }
+
+        if game.quests[index].status == QuestStatus::ObjectiveDone {
+            complete_quest(index, game, objects);
+        }
+    }
+}
+
+fn complete_quest(index: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    game.quests[index].status = QuestStatus::Completed;
+    let quest = game.quests[index].clone();
+    game.messages
+        .add(format!("Quest complete: {}", quest.name), LIGHT_CYAN);
+    game.flags.push(format!("{}_done", quest.id));
+
+    let reward = quest.reward;
+    if reward.xp > 0 {
+        objects[PLAYER].get_xp(reward.xp);
+        game.messages.add(
+            format!("You gain {} experience points", reward.xp),
+            LIGHT_CYAN,
+        );
+    }
+    if reward.gold > 0 {
+        game.gold += reward.gold;
+        game.messages
+            .add(format!("You receive {} gold", reward.gold), GOLD);
+    }
+    let (x, y) = objects[PLAYER].pos();
+    for kind in reward.items {
+        let item = make_item(kind, x, y, game.dungeon_level);
+        if let Some(item) = add_to_inventory(item, &objects[PLAYER].attributes(), game) {
+            objects.push(item);
+        }
+    }
+}
+
+fn quest_log(game: &Game, tcod: &mut Tcod) {
+    let mut text = String::from("Quest log\n");
+    for (status, title) in &[
+        (QuestStatus::Active, "Active"),
+        (QuestStatus::Completed, "Completed"),
+        (QuestStatus::Failed, "Failed"),
+    ] {
+        let quests: Vec<&Quest> = game
+            .quests
+            .iter()
+            .filter(|quest| quest.status == *status)
+            .collect();
+        if quests.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("\n{}:\n", title));
+        for quest in quests {
+            text.push_str(&format!("* {}: {}\n", quest.name, quest.objective));
+        }
+    }
+    if game.quests.is_empty() {
+        text.push_str("\nYou have no quests.\n");
+    }
+    msgbox(&text, QUEST_LOG_WIDTH, tcod);
+}
+
+// a way of turning some items into another, as listed in recipes.json
+#[derive(Clone, Debug, Deserialize)]
+struct Recipe {
+    name: String,
+    ingredients: Vec<Ingredient>,
+    // recipes that improve an item instead of making one need an item of this category
+    #[serde(default)]
+    target: Option<ItemCategory>,
+    result: Craft,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Ingredient {
+    item: String,
+    #[serde(default = "one")]
+    count: i32,
+}
+
+fn one() -> i32 {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+enum Craft {
+    Make {
+        item: Item,
+        #[serde(default)]
+        blessing: Option<Blessing>,
+    },
+    Enchant(Enchantment),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct Enchantment {
+    #[serde(default)]
+    power: i32,
+    #[serde(default)]
+    defense: i32,
+    #[serde(default)]
+    max_hp: i32,
+    #[serde(default)]
+    durability: i32,
+    #[serde(default)]
+    on_hit: Option<OnHit>,
+}
+
+fn load_recipes() -> Result<Vec<Recipe>, Box<dyn Error>> {
+    let mut json = String::new();
+    let mut file = File::open("recipes.json")?;
+    file.read_to_string(&mut json)?;
+    let recipes = serde_json::from_str::<Vec<Recipe>>(&json)?;
+    Ok(recipes)
+}
+
+// unidentified items don't count, the player can't know what they are
+// cursed items spoil whatever they are mixed into, so they don't count as ingredients
+fn is_ingredient(item: &Object, name: &str) -> bool {
+    item.name == name && item.blessing != Blessing::Cursed
+}
+
+fn count_owned(name: &str, game: &Game) -> i32 {
+    if !game.is_identified(name) {
+        return 0;
+    }
+    game.inventory
+        .iter()
+        .filter(|item| is_ingredient(item, name))
+        .map(|item| item.count)
+        .sum()
+}
+
+// the inventory items a recipe could improve
+fn craft_targets(recipe: &Recipe, game: &Game) -> Vec<usize> {
+    (0..game.inventory.len())
+        .filter(|&id| {
+            let item = &game.inventory[id];
+            item.item.map(|item| item.category()) == recipe.target
+                && item.equipment.is_some_and(|e| e.slot != Slot::Ammunition)
+        })
+        .collect()
+}
+
+fn can_craft(recipe: &Recipe, game: &Game) -> bool {
+    recipe
+        .ingredients
+        .iter()
+        .all(|ingredient| count_owned(&ingredient.item, game) >= ingredient.count)
+        && (recipe.target.is_none() || !craft_targets(recipe, game).is_empty())
+}
+
+fn use_up_ingredients(recipe: &Recipe, game: &mut Game) {
+    for ingredient in &recipe.ingredients {
+        let mut left = ingredient.count;
+        while left > 0 {
+            let id = match game
+                .inventory
+                .iter()
+                .position(|item| is_ingredient(item, &ingredient.item))
+            {
+                Some(id) => id,
+                None => break,
+            };
+            let amount = cmp::min(left, game.inventory[id].count);
+            take_from_inventory(id, amount, game);
+            left -= amount;
+        }
+    }
+}
+
+fn crafting_menu(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let recipes = match load_recipes() {
+        Ok(recipes) => recipes,
+        Err(_) => {
+            game.messages.add("You can't remember any recipes", YELLOW);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+    let recipes: Vec<Recipe> = recipes
+        .into_iter()
+        .filter(|recipe| can_craft(recipe, game))
+        .collect();
+    if recipes.is_empty() {
+        game.messages
+            .add("You don't have what it takes to craft anything", YELLOW);
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let options: Vec<String> = recipes
+        .iter()
+        .map(|recipe| {
+            let ingredients: Vec<String> = recipe
+                .ingredients
+                .iter()
+                .map(|ingredient| format!("{} {}", ingredient.count, ingredient.item))
+                .collect();
+            format!("{} ({})", recipe.name, ingredients.join(", "))
+        })
+        .collect();
+    match menu(
+        "Choose something to craft\n",
+        &options,
+        INVENTORY_WIDTH,
+        tcod,
+    ) {
+        Some(choice) => craft(&recipes[choice], tcod, game, objects),
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+fn craft(
+    recipe: &Recipe,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> PlayerAction {
+    match recipe.result {
+        Craft::Make { item, blessing } => {
+            use_up_ingredients(recipe, game);
+            let (x, y) = objects[PLAYER].pos();
+            let mut made = make_item(item, x, y, game.dungeon_level);
+            if let Some(blessing) = blessing {
+                made.blessing = blessing;
+            }
+            game.messages
+                .add(format!("You make {}", made.display_name(game)), LIGHT_GREEN);
+            if let Some(made) = add_to_inventory(made, &objects[PLAYER].attributes(), game) {
+                objects.push(made);
+            }
+        }
+        Craft::Enchant(enchantment) => {
+            let targets = craft_targets(recipe, game);
+            let options: Vec<String> = targets
+                .iter()
+                .map(|&id| game.inventory[id].display_name(game))
+                .collect();
+            let target = match menu(
+                "Choose an item to improve\n",
+                &options,
+                INVENTORY_WIDTH,
+                tcod,
+            ) {
+                Some(choice) => targets[choice],
+                None => return PlayerAction::DidntTakeTurn,
+            };
+
+            let name = game.inventory[target].name.clone();
+            if let Some(ref mut equipment) = game.inventory[target].equipment {
+                equipment.power_bonus += enchantment.power;
+                equipment.defense_bonus += enchantment.defense;
+                equipment.max_hp_bonus += enchantment.max_hp;
+                equipment.max_durability += enchantment.durability;
+                equipment.durability += enchantment.durability;
+                if enchantment.on_hit.is_some() {
+                    equipment.on_hit = enchantment.on_hit;
+                }
+            }
+            // the target is improved first, as using up ingredients shifts the inventory
+            use_up_ingredients(recipe, game);
+            game.messages
+                .add(format!("Your {} is improved", name), LIGHT_GREEN);
+        }
+    }
+    PlayerAction::TookTurn
+}
+
 fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
     let save_data = serde_json::to_string(&(game, objects))?;
     let mut file = File::create("gamesave.json")?;
@@ -2293,6 +8034,19 @@ fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// defaults for the fields older saves don't have yet
+fn start_nutrition() -> i32 {
+    START_NUTRITION
+}
+
+fn already_identified() -> bool {
+    true
+}
+
+fn full_durability() -> i32 {
+    EQUIPMENT_DURABILITY
+}
+
 fn load_game() -> Result<(Game, Vec<Object>), Box<dyn Error>> {
     let mut json_save_state = String::new();
     let mut file = File::open("gamesave.json")?;
@@ -2302,6 +8056,12 @@ fn load_game() -> Result<(Game, Vec<Object>), Box<dyn Error>> {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "--damage-table" {
+        print_damage_table(&args[2..]);
+        return;
+    }
+
     let root = Root::initializer()
         .font("fonts/arial10x10.png", FontLayout::Tcod)
         .font_type(FontType::Greyscale)